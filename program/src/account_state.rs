@@ -1,19 +1,834 @@
 //! @brief account_state manages account data
 
 use crate::error::SampleError;
-use sol_template_shared::ACCOUNT_STATE_SPACE;
+use borsh::{BorshDeserialize, BorshSerialize};
+use sol_template_shared::TYPED_HEADER;
+pub use sol_template_shared::AccountType;
 use solana_program::{
+    borsh::get_instance_packed_len,
     entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    msg,
     program_error::ProgramError,
-    program_pack::{IsInitialized, Pack, Sealed},
+    program_pack::IsInitialized,
+    pubkey::Pubkey,
 };
 use std::collections::BTreeMap;
 
+/// Seed prefix of the program derived wallet accounts
+pub const WALLET_SEED: &[u8] = b"wallet";
+
+/// Derives the wallet account address of an owner, optionally
+/// distinguished by an index so one owner can hold several wallets
+pub fn find_wallet_address(program_id: &Pubkey, owner: &Pubkey, index: Option<u8>) -> (Pubkey, u8) {
+    match index {
+        Some(index) => {
+            Pubkey::find_program_address(&[WALLET_SEED, owner.as_ref(), &[index]], program_id)
+        }
+        None => Pubkey::find_program_address(&[WALLET_SEED, owner.as_ref()], program_id),
+    }
+}
+
+/// Seed prefix of the program derived authority holding a wallets tokens
+pub const AUTHORITY_SEED: &[u8] = b"authority";
+
+/// Derives the authority that owns the token accounts of a wallet
+pub fn find_wallet_authority_address(program_id: &Pubkey, wallet: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[AUTHORITY_SEED, wallet.as_ref()], program_id)
+}
+
+/// Seed of the program config account
+pub const CONFIG_SEED: &[u8] = b"config";
+
+/// Derives the address of the program config account
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
+
+/// Most fee shares a fee schedule holds besides the treasury
+pub const MAX_FEE_SHARES: usize = 4;
+
+/// Share of the service fees paid to a recipient other than the treasury,
+/// such as a referrer or an integrator
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct FeeShare {
+    pub recipient: Pubkey,
+    /// Share of each fee, in basis points
+    pub basis_points: u16,
+}
+
+/// Amounts charged for each service, in lamports or in tokens of the fee mint
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct FeeSchedule {
+    pub minting: u64,
+    pub transferring: u64,
+    pub burning: u64,
+    /// Mint of the tokens paying the fees, lamports are charged without one
+    pub mint: Option<Pubkey>,
+    /// Fee on wallet transfers, in basis points of the amount sent
+    pub transfer_basis_points: u16,
+    /// Least fee taken from a wallet transfer
+    pub transfer_fee_min: u64,
+    /// Most fee taken from a wallet transfer
+    pub transfer_fee_max: u64,
+    /// Shares of the service fees split off to other recipients, the
+    /// treasury keeps the rest
+    pub shares: Vec<FeeShare>,
+    /// Share of the service fees credited to the referral account passed
+    /// along, out of the treasury part, in basis points
+    pub referral_basis_points: u16,
+}
+
+impl FeeSchedule {
+    /// Reads a fee schedule packed by the given config version, leaving the
+    /// fields introduced by later versions at their defaults
+    fn deserialize_version(version: u8, buf: &mut &[u8]) -> std::io::Result<Self> {
+        let mut fees = FeeSchedule {
+            minting: u64::deserialize(buf)?,
+            transferring: u64::deserialize(buf)?,
+            burning: u64::deserialize(buf)?,
+            ..FeeSchedule::default()
+        };
+        if version >= 2 {
+            fees.mint = Option::<Pubkey>::deserialize(buf)?;
+        }
+        if version >= 3 {
+            fees.transfer_basis_points = u16::deserialize(buf)?;
+            fees.transfer_fee_min = u64::deserialize(buf)?;
+            fees.transfer_fee_max = u64::deserialize(buf)?;
+        }
+        if version >= 4 {
+            fees.shares = Vec::<FeeShare>::deserialize(buf)?;
+        }
+        if version >= 5 {
+            fees.referral_basis_points = u16::deserialize(buf)?;
+        }
+        Ok(fees)
+    }
+}
+
+/// Program wide settings, held by the program config account. Fields are
+/// only ever appended to the fee schedule, which comes last, so configs
+/// packed by earlier versions remain readable.
+#[derive(BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct ProgramConfig {
+    /// The only signer allowed to change the config
+    pub admin: Pubkey,
+    /// Treasury account credited with the service fees
+    pub fee_recipient: Pubkey,
+    /// Fees charged by the `*WithFee` instructions
+    pub fees: FeeSchedule,
+}
+
+impl ProgramConfig {
+    /// Layout version of the content following the config discriminator,
+    /// version 2 added the fee mint, version 3 the wallet transfer fee,
+    /// version 4 the fee shares and version 5 the referral share
+    pub const VERSION: u8 = 5;
+
+    /// Reads a config packed by the given version
+    fn deserialize_version(version: u8, buf: &mut &[u8]) -> std::io::Result<Self> {
+        Ok(ProgramConfig {
+            admin: Pubkey::deserialize(buf)?,
+            fee_recipient: Pubkey::deserialize(buf)?,
+            fees: FeeSchedule::deserialize_version(version, buf)?,
+        })
+    }
+    /// Returns the account size needed to pack the config
+    pub fn packed_len(&self) -> Result<usize, ProgramError> {
+        Ok(TYPED_HEADER + get_instance_packed_len(self)?)
+    }
+    /// Retrieve the config from the config account data area
+    pub fn unpack_account(src: &[u8]) -> Result<Self, ProgramError> {
+        let (version, mut content) = unpack_typed(src, AccountType::Config, Self::VERSION)?;
+        Self::deserialize_version(version, &mut content).map_err(|error| {
+            msg!("Fail: Unpacking program config: {}", error);
+            SampleError::AccountStateSerializationFailure.into()
+        })
+    }
+    /// Store the config to the config account data area, failing when the
+    /// account is too small to hold it
+    pub fn pack_account(&self, dst: &mut [u8]) -> ProgramResult {
+        pack_typed(AccountType::Config, Self::VERSION, self, dst)
+    }
+}
+
+/// Seed prefix of the referral accounts
+pub const REFERRAL_SEED: &[u8] = b"referral";
+
+/// Derives the referral account address of a referrer
+pub fn find_referral_address(program_id: &Pubkey, referrer: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[REFERRAL_SEED, referrer.as_ref()], program_id)
+}
+
+/// Referral account collecting the referral share of the service fees until
+/// its referrer claims them
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct ReferralAccount {
+    /// The only signer allowed to claim the rewards
+    pub referrer: Pubkey,
+}
+
+impl ReferralAccount {
+    /// Layout version of the content following the referral discriminator
+    pub const VERSION: u8 = 1;
+
+    /// Returns the account size needed to pack the referral account
+    pub fn packed_len(&self) -> Result<usize, ProgramError> {
+        Ok(TYPED_HEADER + get_instance_packed_len(self)?)
+    }
+    /// Retrieve the referral account from its data area
+    pub fn unpack_account(src: &[u8]) -> Result<Self, ProgramError> {
+        let (_, mut content) = unpack_typed(src, AccountType::Referral, Self::VERSION)?;
+        Self::deserialize(&mut content).map_err(|error| {
+            msg!("Fail: Unpacking referral account: {}", error);
+            SampleError::AccountStateSerializationFailure.into()
+        })
+    }
+    /// Store the referral account to its data area
+    pub fn pack_account(&self, dst: &mut [u8]) -> ProgramResult {
+        pack_typed(AccountType::Referral, Self::VERSION, self, dst)
+    }
+}
+
+/// Seed of the fee tiers account
+pub const FEE_TIERS_SEED: &[u8] = b"fee_tiers";
+
+/// Derives the address of the fee tiers account
+pub fn find_fee_tiers_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[FEE_TIERS_SEED], program_id)
+}
+
+/// Discount on the service fees a payer gets entirely
+pub const FEE_EXEMPT_DISCOUNT: u8 = 100;
+
+/// Discounts on the service fees granted by the config admin, in percent of
+/// the fees, to the accounts paying them
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct FeeTiers {
+    discounts: BTreeMap<Pubkey, u8>,
+}
+
+impl FeeTiers {
+    /// Layout version of the content following the fee tiers discriminator
+    pub const VERSION: u8 = 1;
+
+    /// Returns the discount of the payer, in percent
+    pub fn discount(&self, payer: &Pubkey) -> u8 {
+        self.discounts.get(payer).copied().unwrap_or(0)
+    }
+    /// Sets the discount of the payer, a discount of 0 drops the payer
+    pub fn set_discount(&mut self, payer: Pubkey, discount: u8) {
+        match discount {
+            0 => self.discounts.remove(&payer),
+            _ => self.discounts.insert(payer, discount),
+        };
+    }
+    /// Returns the account size needed to pack the fee tiers
+    pub fn packed_len(&self) -> Result<usize, ProgramError> {
+        Ok(TYPED_HEADER + get_instance_packed_len(self)?)
+    }
+    /// Retrieve the fee tiers from the fee tiers account data area
+    pub fn unpack_account(src: &[u8]) -> Result<Self, ProgramError> {
+        let (_, mut content) = unpack_typed(src, AccountType::FeeTiers, Self::VERSION)?;
+        Self::deserialize(&mut content).map_err(|error| {
+            msg!("Fail: Unpacking fee tiers: {}", error);
+            SampleError::AccountStateSerializationFailure.into()
+        })
+    }
+    /// Store the fee tiers to the fee tiers account data area
+    pub fn pack_account(&self, dst: &mut [u8]) -> ProgramResult {
+        pack_typed(AccountType::FeeTiers, Self::VERSION, self, dst)
+    }
+}
+
+/// Reads the header of an account without account state, checking its type
+/// and version, and returns the version with the content following it
+fn unpack_typed(
+    src: &[u8],
+    expected: AccountType,
+    latest_version: u8,
+) -> Result<(u8, &[u8]), ProgramError> {
+    let (account_type, version, content) =
+        sol_template_shared::unpack_typed_header(src).map_err(|error| {
+            msg!("Fail: Unpacking {} account: {}", expected, error);
+            SampleError::from(error)
+        })?;
+    if account_type != expected {
+        msg!("Fail: The account is a {} and not a {}.", account_type, expected);
+        return Err(SampleError::AccountTypeMismatch.into());
+    }
+    if version == 0 || version > latest_version {
+        msg!("Fail: Unsupported {} version {}.", expected, version);
+        return Err(SampleError::UnsupportedAccountVersion.into());
+    }
+    Ok((version, content))
+}
+
+/// Writes the header and content of an account without account state,
+/// failing when the account is too small to hold them
+fn pack_typed<T: BorshSerialize>(
+    account_type: AccountType,
+    version: u8,
+    content: &T,
+    dst: &mut [u8],
+) -> ProgramResult {
+    let mut content_dst = sol_template_shared::pack_typed_header(account_type, version, dst)
+        .map_err(SampleError::from)?;
+    content.serialize(&mut content_dst).map_err(|error| {
+        msg!("Fail: Packing {} account: {}", account_type, error);
+        SampleError::AccountStorageFull.into()
+    })
+}
+
+/// Most members a multisig wallet holds
+pub const MAX_MULTISIG_MEMBERS: usize = 10;
+
+/// Members of a multisig wallet, any threshold of which approve its transfers
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct Multisig {
+    pub members: Vec<Pubkey>,
+    /// Approvals a proposal needs before it can be executed
+    pub threshold: u8,
+    /// Index of the next proposal, seeding its address
+    pub proposal_count: u64,
+}
+
+impl Multisig {
+    /// Tells whether the key is one of the members
+    pub fn is_member(&self, key: &Pubkey) -> bool {
+        self.members.contains(key)
+    }
+}
+
+/// Cap on what a wallet sends of an asset over a rolling period, what was
+/// sent being regained at an even rate over the period that follows
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct SpendingLimit {
+    /// Mint of the tokens limited, lamports are limited without one
+    pub mint: Option<Pubkey>,
+    /// Most the wallet has spent at any time, regained in full over a period
+    pub amount: u64,
+    /// Length of a period, in seconds
+    pub period: i64,
+    /// Unix timestamp up to which the spent amount regained was counted
+    pub updated_at: i64,
+    /// What the wallet sent, less what it regained since
+    pub spent: u64,
+}
+
+impl SpendingLimit {
+    /// Deducts what was regained by the given unix timestamp from the spent
+    /// amount, carrying the seconds short of regaining a whole unit over
+    pub fn regain(&mut self, now: i64) {
+        let elapsed = now.saturating_sub(self.updated_at);
+        if self.spent == 0 || self.amount == 0 || self.period <= 0 || elapsed <= 0 {
+            self.updated_at = self.updated_at.max(now);
+            return;
+        }
+        let regained = self.amount as u128 * elapsed as u128 / self.period as u128;
+        if regained >= self.spent as u128 {
+            self.spent = 0;
+            self.updated_at = now;
+        } else {
+            // Less than spent, so both fit
+            self.spent -= regained as u64;
+            self.updated_at += (regained * self.period as u128 / self.amount as u128) as i64;
+        }
+    }
+    /// Counts the amount against the limit at the given unix timestamp,
+    /// once what was regained since the last transfer was deducted
+    pub fn spend(&mut self, now: i64, amount: u64) -> Result<(), SampleError> {
+        self.regain(now);
+        self.spent = self
+            .spent
+            .checked_add(amount)
+            .filter(|spent| *spent <= self.amount)
+            .ok_or(SampleError::SpendingLimitExceeded)?;
+        Ok(())
+    }
+}
+
+/// Seconds the owner has to veto a recovery once the guardians approved it
+pub const RECOVERY_DELAY: i64 = 3 * 24 * 60 * 60;
+
+/// Handover of a wallet to a new owner, initiated by one of its guardians
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct PendingRecovery {
+    pub new_owner: Pubkey,
+    /// Guardians approving the handover
+    pub approvals: Vec<Pubkey>,
+    /// Unix timestamp from which the recovery can be finalized, set once
+    /// the approvals reach the recovery threshold
+    pub unlock_time: Option<i64>,
+}
+
+impl PendingRecovery {
+    /// Records the approval of the guardian at the given unix timestamp,
+    /// starting the veto period once the approvals reach the threshold
+    pub fn approve(&mut self, guardian: Pubkey, threshold: u8, now: i64) {
+        self.approvals.push(guardian);
+        if self.unlock_time.is_none() && self.approvals.len() >= threshold as usize {
+            self.unlock_time = Some(now.saturating_add(RECOVERY_DELAY));
+        }
+    }
+}
+
+/// Most session keys a wallet holds
+pub const MAX_SESSION_KEYS: usize = 10;
+
+/// Delegate signing for the owner of a wallet, until its expiry slot, only
+/// the allowed instructions and within its budgets
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct SessionKey {
+    pub key: Pubkey,
+    /// Last slot the key signs in
+    pub expiry_slot: u64,
+    /// Lamports the key still transfers out of the wallet
+    pub lamport_budget: u64,
+    /// Tokens, of any mint, the key still transfers out of the wallet
+    pub token_budget: u64,
+    /// Tags of the instructions the key signs, see `ProgramInstruction::tag`
+    pub allowed_instructions: Vec<u8>,
+}
+
+impl SessionKey {
+    /// Counts the lamports and tokens transferred against the budgets
+    pub fn charge(&mut self, lamports: u64, tokens: u64) -> Result<(), SampleError> {
+        match (
+            self.lamport_budget.checked_sub(lamports),
+            self.token_budget.checked_sub(tokens),
+        ) {
+            (Some(lamport_budget), Some(token_budget)) => {
+                self.lamport_budget = lamport_budget;
+                self.token_budget = token_budget;
+                Ok(())
+            }
+            _ => Err(SampleError::SessionBudgetExceeded),
+        }
+    }
+}
+
+/// Most allowances a wallet holds
+pub const MAX_ALLOWANCES: usize = 10;
+
+/// Tokens of a mint a spender transfers out of a wallet until it expires
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct Allowance {
+    pub spender: Pubkey,
+    pub mint: Pubkey,
+    /// Tokens the spender still transfers
+    pub amount: u64,
+    /// Unix timestamp from which the allowance can no longer be spent
+    pub expiry: i64,
+}
+
+impl Allowance {
+    /// Takes the amount out of the allowance at the given unix timestamp
+    pub fn spend(&mut self, now: i64, amount: u64) -> Result<(), SampleError> {
+        if now >= self.expiry {
+            return Err(SampleError::AllowanceExpired);
+        }
+        self.amount = self
+            .amount
+            .checked_sub(amount)
+            .ok_or(SampleError::AllowanceExceeded)?;
+        Ok(())
+    }
+}
+
+/// Settings of a wallet, held in the extension of its account state. Fields
+/// are only ever appended, wallets packed before a field existed read it as
+/// its default.
+#[derive(BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct WalletSettings {
+    /// Members approving the transfers of a multisig wallet, whose owner
+    /// can not transfer on its own
+    pub multisig: Option<Multisig>,
+    /// Spending limits of the wallet, at most one per asset
+    pub spending_limits: Vec<SpendingLimit>,
+    /// Signers allowed to cancel the pending withdrawals besides the owner
+    /// and to recover the wallet for a new owner
+    pub guardians: Vec<Pubkey>,
+    /// Seconds a withdrawal waits in the queue, transfers out of a wallet
+    /// with a delay have to be queued
+    pub withdrawal_delay: i64,
+    /// Index of the next pending withdrawal, seeding its address
+    pub withdrawal_count: u64,
+    /// Guardian approvals recovering the wallet, recovery is disabled at 0
+    pub recovery_threshold: u8,
+    /// Recovery initiated by the guardians
+    pub recovery: Option<PendingRecovery>,
+    /// Delegates signing for the owner
+    pub session_keys: Vec<SessionKey>,
+    /// Index of the next subscription, seeding its address
+    pub subscription_count: u64,
+    /// Tokens spenders transfer out of the wallet, at most one per spender
+    /// and mint
+    pub allowances: Vec<Allowance>,
+    /// Lamports transferred out of a time-locked wallet at once without
+    /// being queued, token transfers are queued whatever their amount
+    pub withdrawal_threshold: u64,
+    /// Lowered withdrawal delay or raised threshold, waiting out the
+    /// current delay
+    pub withdrawal_policy_change: Option<WithdrawalPolicyChange>,
+    /// Number of times the wallet was recovered, invalidating the pending
+    /// withdrawals and subscriptions of the previous owners
+    pub owner_epoch: u64,
+}
+
+impl WalletSettings {
+    /// Reads the settings from the account state extension, leaving the
+    /// fields past its end at their defaults
+    fn deserialize_extension(buf: &mut &[u8]) -> std::io::Result<Self> {
+        let mut settings = WalletSettings::default();
+        if !buf.is_empty() {
+            settings.multisig = Option::<Multisig>::deserialize(buf)?;
+        }
+        if !buf.is_empty() {
+            settings.spending_limits = Vec::<SpendingLimit>::deserialize(buf)?;
+        }
+        if !buf.is_empty() {
+            settings.guardians = Vec::<Pubkey>::deserialize(buf)?;
+            settings.withdrawal_delay = i64::deserialize(buf)?;
+            settings.withdrawal_count = u64::deserialize(buf)?;
+        }
+        if !buf.is_empty() {
+            settings.recovery_threshold = u8::deserialize(buf)?;
+            settings.recovery = Option::<PendingRecovery>::deserialize(buf)?;
+        }
+        if !buf.is_empty() {
+            settings.session_keys = Vec::<SessionKey>::deserialize(buf)?;
+        }
+        if !buf.is_empty() {
+            settings.subscription_count = u64::deserialize(buf)?;
+        }
+        if !buf.is_empty() {
+            settings.allowances = Vec::<Allowance>::deserialize(buf)?;
+        }
+        if !buf.is_empty() {
+            settings.withdrawal_threshold = u64::deserialize(buf)?;
+            settings.withdrawal_policy_change =
+                Option::<WithdrawalPolicyChange>::deserialize(buf)?;
+        }
+        if !buf.is_empty() {
+            settings.owner_epoch = u64::deserialize(buf)?;
+        }
+        Ok(settings)
+    }
+    /// Returns the spending limit of the asset, lamports without a mint
+    pub fn spending_limit_mut(&mut self, mint: Option<&Pubkey>) -> Option<&mut SpendingLimit> {
+        self.spending_limits
+            .iter_mut()
+            .find(|limit| limit.mint.as_ref() == mint)
+    }
+    /// Returns the session key of the delegate
+    pub fn session_key_mut(&mut self, key: &Pubkey) -> Option<&mut SessionKey> {
+        self.session_keys
+            .iter_mut()
+            .find(|session_key| session_key.key == *key)
+    }
+    /// Returns the allowance of the spender on the tokens of the mint
+    pub fn allowance_mut(&mut self, spender: &Pubkey, mint: &Pubkey) -> Option<&mut Allowance> {
+        self.allowances
+            .iter_mut()
+            .find(|allowance| allowance.spender == *spender && allowance.mint == *mint)
+    }
+}
+
+/// Seed prefix of the multisig proposal accounts
+pub const PROPOSAL_SEED: &[u8] = b"proposal";
+
+/// Derives the address of a proposal of the multisig wallet from its index
+pub fn find_proposal_address(program_id: &Pubkey, wallet: &Pubkey, index: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[PROPOSAL_SEED, wallet.as_ref(), &index.to_le_bytes()],
+        program_id,
+    )
+}
+
+/// Account of a proposed instruction, like an `AccountMeta`
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
+pub struct ProposedAccountMeta {
+    pub pubkey: Pubkey,
+    pub is_signer: bool,
+    pub is_writable: bool,
+}
+
+/// Instruction of any program proposed to a multisig wallet, invoked with
+/// the wallet authority signing once approved
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
+pub struct ProposedInstruction {
+    pub program_id: Pubkey,
+    pub accounts: Vec<ProposedAccountMeta>,
+    pub data: Vec<u8>,
+}
+
+impl From<Instruction> for ProposedInstruction {
+    fn from(instruction: Instruction) -> Self {
+        ProposedInstruction {
+            program_id: instruction.program_id,
+            accounts: instruction
+                .accounts
+                .into_iter()
+                .map(|meta| ProposedAccountMeta {
+                    pubkey: meta.pubkey,
+                    is_signer: meta.is_signer,
+                    is_writable: meta.is_writable,
+                })
+                .collect(),
+            data: instruction.data,
+        }
+    }
+}
+
+impl From<ProposedInstruction> for Instruction {
+    fn from(proposed: ProposedInstruction) -> Self {
+        Instruction {
+            program_id: proposed.program_id,
+            accounts: proposed
+                .accounts
+                .into_iter()
+                .map(|meta| AccountMeta {
+                    pubkey: meta.pubkey,
+                    is_signer: meta.is_signer,
+                    is_writable: meta.is_writable,
+                })
+                .collect(),
+            data: proposed.data,
+        }
+    }
+}
+
+/// Action a multisig proposal takes on behalf of the wallet once approved
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
+pub enum ProposalAction {
+    /// Transfer lamports out of the wallet
+    TransferLamports { destination: Pubkey, amount: u64 },
+    /// Transfer tokens out of a token account held by the wallet authority
+    TransferSpl {
+        source: Pubkey,
+        mint: Pubkey,
+        destination: Pubkey,
+        amount: u64,
+        decimals: u8,
+    },
+    /// Invoke an instruction of another program
+    Invoke(ProposedInstruction),
+}
+
+/// Where a multisig proposal stands
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Debug, PartialEq)]
+pub enum ProposalStatus {
+    /// Collecting votes, or approved and waiting to be executed
+    Pending,
+    Executed,
+    /// Rejected by enough members that the threshold can not be met
+    Rejected,
+}
+
+/// Proposal of an action on a multisig wallet and the votes of its members
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
+pub struct Proposal {
+    pub wallet: Pubkey,
+    pub action: ProposalAction,
+    pub approvals: Vec<Pubkey>,
+    pub rejections: Vec<Pubkey>,
+    pub status: ProposalStatus,
+}
+
+impl Proposal {
+    /// Layout version of the content following the proposal discriminator
+    pub const VERSION: u8 = 1;
+
+    /// Tells whether the member approved or rejected the proposal already
+    pub fn has_voted(&self, member: &Pubkey) -> bool {
+        self.approvals.contains(member) || self.rejections.contains(member)
+    }
+    /// Returns the account size needed to pack the proposal once the given
+    /// number of further votes are in
+    pub fn packed_len(&self, votes: usize) -> Result<usize, ProgramError> {
+        Ok(TYPED_HEADER + get_instance_packed_len(self)? + votes * std::mem::size_of::<Pubkey>())
+    }
+    /// Retrieve the proposal from the proposal account data area
+    pub fn unpack_account(src: &[u8]) -> Result<Self, ProgramError> {
+        let (_, mut content) = unpack_typed(src, AccountType::Proposal, Self::VERSION)?;
+        Self::deserialize(&mut content).map_err(|error| {
+            msg!("Fail: Unpacking proposal: {}", error);
+            SampleError::AccountStateSerializationFailure.into()
+        })
+    }
+    /// Store the proposal to the proposal account data area
+    pub fn pack_account(&self, dst: &mut [u8]) -> ProgramResult {
+        pack_typed(AccountType::Proposal, Self::VERSION, self, dst)
+    }
+}
+
+/// Most guardians a wallet holds
+pub const MAX_GUARDIANS: usize = 10;
+
+/// Longest withdrawal delay in seconds, beyond which the wallet would be
+/// frozen for good
+pub const MAX_WITHDRAWAL_DELAY: i64 = 30 * 24 * 60 * 60;
+
+/// Withdrawal delay and threshold loosening the time lock of a wallet, which
+/// only take effect once the delay they replace passed
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+pub struct WithdrawalPolicyChange {
+    pub withdrawal_delay: i64,
+    pub withdrawal_threshold: u64,
+    /// Unix timestamp from which the change can be applied
+    pub effective_time: i64,
+}
+
+/// Seed prefix of the pending withdrawal accounts
+pub const WITHDRAWAL_SEED: &[u8] = b"withdrawal";
+
+/// Derives the address of a pending withdrawal of the wallet from its index
+pub fn find_withdrawal_address(program_id: &Pubkey, wallet: &Pubkey, index: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[WITHDRAWAL_SEED, wallet.as_ref(), &index.to_le_bytes()],
+        program_id,
+    )
+}
+
+/// Transfer out of a time-locked wallet waiting for its unlock time
+#[derive(BorshSerialize, Clone, Debug, PartialEq)]
+pub struct PendingWithdrawal {
+    pub wallet: Pubkey,
+    /// Lamport or token transfer taking place once unlocked
+    pub action: ProposalAction,
+    /// Unix timestamp from which the withdrawal can be executed
+    pub unlock_time: i64,
+    /// Owner epoch of the wallet when queued
+    pub owner_epoch: u64,
+}
+
+impl PendingWithdrawal {
+    /// Layout version of the content following the withdrawal discriminator,
+    /// version 2 added the owner epoch
+    pub const VERSION: u8 = 2;
+
+    /// Reads a withdrawal packed by the given version
+    fn deserialize_version(version: u8, buf: &mut &[u8]) -> std::io::Result<Self> {
+        let mut withdrawal = PendingWithdrawal {
+            wallet: Pubkey::deserialize(buf)?,
+            action: ProposalAction::deserialize(buf)?,
+            unlock_time: i64::deserialize(buf)?,
+            owner_epoch: 0,
+        };
+        if version >= 2 {
+            withdrawal.owner_epoch = u64::deserialize(buf)?;
+        }
+        Ok(withdrawal)
+    }
+    /// Returns the account size needed to pack the withdrawal
+    pub fn packed_len(&self) -> Result<usize, ProgramError> {
+        Ok(TYPED_HEADER + get_instance_packed_len(self)?)
+    }
+    /// Retrieve the withdrawal from the pending withdrawal account data area
+    pub fn unpack_account(src: &[u8]) -> Result<Self, ProgramError> {
+        let (version, mut content) = unpack_typed(src, AccountType::Withdrawal, Self::VERSION)?;
+        Self::deserialize_version(version, &mut content).map_err(|error| {
+            msg!("Fail: Unpacking pending withdrawal: {}", error);
+            SampleError::AccountStateSerializationFailure.into()
+        })
+    }
+    /// Store the withdrawal to the pending withdrawal account data area
+    pub fn pack_account(&self, dst: &mut [u8]) -> ProgramResult {
+        pack_typed(AccountType::Withdrawal, Self::VERSION, self, dst)
+    }
+}
+
+/// Seed prefix of the subscription accounts
+pub const SUBSCRIPTION_SEED: &[u8] = b"subscription";
+
+/// Derives the address of a subscription to the wallet from its index
+pub fn find_subscription_address(program_id: &Pubkey, wallet: &Pubkey, index: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[SUBSCRIPTION_SEED, wallet.as_ref(), &index.to_le_bytes()],
+        program_id,
+    )
+}
+
+/// Transfer out of a wallet to a payee, collected once every period
+#[derive(BorshSerialize, Clone, Debug, PartialEq)]
+pub struct Subscription {
+    pub wallet: Pubkey,
+    /// Lamport or token transfer to the payee, of the amount due each period
+    pub action: ProposalAction,
+    /// Length of a period, in seconds
+    pub period: i64,
+    /// Unix timestamp from which the next payment can be collected
+    pub next_due: i64,
+    /// Owner epoch of the wallet when subscribed
+    pub owner_epoch: u64,
+}
+
+impl Subscription {
+    /// Layout version of the content following the subscription
+    /// discriminator, version 2 added the owner epoch
+    pub const VERSION: u8 = 2;
+
+    /// Reads a subscription packed by the given version
+    fn deserialize_version(version: u8, buf: &mut &[u8]) -> std::io::Result<Self> {
+        let mut subscription = Subscription {
+            wallet: Pubkey::deserialize(buf)?,
+            action: ProposalAction::deserialize(buf)?,
+            period: i64::deserialize(buf)?,
+            next_due: i64::deserialize(buf)?,
+            owner_epoch: 0,
+        };
+        if version >= 2 {
+            subscription.owner_epoch = u64::deserialize(buf)?;
+        }
+        Ok(subscription)
+    }
+
+    /// Returns the account size needed to pack the subscription
+    pub fn packed_len(&self) -> Result<usize, ProgramError> {
+        Ok(TYPED_HEADER + get_instance_packed_len(self)?)
+    }
+    /// Moves the next due time past the given unix timestamp, once due, so
+    /// that a single payment is collected however many periods were missed
+    pub fn advance(&mut self, now: i64) -> Result<(), SampleError> {
+        if now < self.next_due {
+            return Err(SampleError::SubscriptionNotDue);
+        }
+        if self.period <= 0 {
+            return Err(SampleError::InvalidSubscription);
+        }
+        self.next_due = now
+            .checked_sub(self.next_due)
+            .and_then(|elapsed| elapsed.checked_div(self.period))
+            .and_then(|periods| periods.checked_add(1))
+            .and_then(|periods| periods.checked_mul(self.period))
+            .and_then(|elapsed| self.next_due.checked_add(elapsed))
+            .ok_or(SampleError::InvalidSubscription)?;
+        Ok(())
+    }
+    /// Retrieve the subscription from the subscription account data area
+    pub fn unpack_account(src: &[u8]) -> Result<Self, ProgramError> {
+        let (version, mut content) = unpack_typed(src, AccountType::Subscription, Self::VERSION)?;
+        Self::deserialize_version(version, &mut content).map_err(|error| {
+            msg!("Fail: Unpacking subscription: {}", error);
+            SampleError::AccountStateSerializationFailure.into()
+        })
+    }
+    /// Store the subscription to the subscription account data area
+    pub fn pack_account(&self, dst: &mut [u8]) -> ProgramResult {
+        pack_typed(AccountType::Subscription, Self::VERSION, self, dst)
+    }
+}
+
 /// Maintains global accumulator
 #[derive(Debug, Default, PartialEq)]
 pub struct ProgramAccountState {
+    account_type: AccountType,
     is_initialized: bool,
+    owner: Pubkey,
     btree_storage: BTreeMap<String, String>,
+    wallet_settings: WalletSettings,
 }
 
 impl ProgramAccountState {
@@ -21,6 +836,30 @@ impl ProgramAccountState {
     pub fn set_initialized(&mut self) {
         self.is_initialized = true;
     }
+    /// Returns the type of the account
+    pub fn account_type(&self) -> AccountType {
+        self.account_type
+    }
+    /// Sets the type of the account
+    pub fn set_account_type(&mut self, account_type: AccountType) {
+        self.account_type = account_type;
+    }
+    /// Returns the owner of the account
+    pub fn owner(&self) -> &Pubkey {
+        &self.owner
+    }
+    /// Sets the owner of the account
+    pub fn set_owner(&mut self, owner: Pubkey) {
+        self.owner = owner;
+    }
+    /// Returns the settings of the wallet
+    pub fn wallet_settings(&self) -> &WalletSettings {
+        &self.wallet_settings
+    }
+    /// Returns the settings of the wallet for changing them
+    pub fn wallet_settings_mut(&mut self) -> &mut WalletSettings {
+        &mut self.wallet_settings
+    }
     /// Adds a new key/value pair to the account
     pub fn add(&mut self, key: String, value: String) -> ProgramResult {
         match self.btree_storage.contains_key(&key) {
@@ -31,6 +870,77 @@ impl ProgramAccountState {
             }
         }
     }
+    /// Returns the account size needed to pack the state
+    pub fn packed_len(&self) -> Result<usize, ProgramError> {
+        Ok(sol_template_shared::packed_len(&self.btree_storage, self.extension()?.len()))
+    }
+    /// Serializes the wallet settings into the account state extension,
+    /// which stays empty as long as they are at their defaults
+    fn extension(&self) -> Result<Vec<u8>, ProgramError> {
+        if self.wallet_settings == WalletSettings::default() {
+            return Ok(Vec::new());
+        }
+        self.wallet_settings.try_to_vec().map_err(|error| {
+            msg!("Fail: Packing wallet settings: {}", error);
+            SampleError::AccountStateSerializationFailure.into()
+        })
+    }
+    /// Retrieve the initialized 'state' of an account of any size
+    pub fn unpack_account(src: &[u8]) -> Result<Self, ProgramError> {
+        let account_state = Self::unpack_from_slice(src)?;
+        match account_state.is_initialized() {
+            true => Ok(account_state),
+            false => Err(ProgramError::UninitializedAccount),
+        }
+    }
+    /// Returns the layout version the account state was packed with
+    pub fn layout_version(src: &[u8]) -> Result<u8, ProgramError> {
+        sol_template_shared::layout_version(src).map_err(|error| {
+            msg!("Fail: Reading account layout version: {}", error);
+            SampleError::from(error).into()
+        })
+    }
+    /// Store 'state' of account to its data area, failing when the account
+    /// is too small to hold it
+    pub fn pack_account(&self, dst: &mut [u8]) -> ProgramResult {
+        sol_template_shared::pack_into_slice(
+            self.account_type,
+            self.is_initialized,
+            &self.owner,
+            &self.btree_storage,
+            &self.extension()?,
+            dst,
+        )
+        .map_err(|error| {
+            msg!("Fail: Packing account state: {}", error);
+            SampleError::from(error).into()
+        })
+    }
+    /// Retrieve 'state' of account from account data area, initialized or
+    /// not. Accounts grow and shrink with their content, so the state is not
+    /// `Pack`, which has no way to report a state that does not fit.
+    pub fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        match sol_template_shared::unpack_from_slice(src) {
+            Ok((account_type, is_initialized, owner, btree_map, extension)) => {
+                let wallet_settings = WalletSettings::deserialize_extension(&mut &extension[..])
+                    .map_err(|error| {
+                        msg!("Fail: Unpacking wallet settings: {}", error);
+                        SampleError::AccountStateSerializationFailure
+                    })?;
+                Ok(ProgramAccountState {
+                    account_type,
+                    is_initialized,
+                    owner,
+                    btree_storage: btree_map,
+                    wallet_settings,
+                })
+            }
+            Err(error) => {
+                msg!("Fail: Unpacking account state: {}", error);
+                Err(SampleError::from(error).into())
+            }
+        }
+    }
     /// Removes a key from account and returns the keys value
     pub fn remove(&mut self, key: &str) -> Result<String, SampleError> {
         match self.btree_storage.contains_key(key) {
@@ -40,30 +950,8 @@ impl ProgramAccountState {
     }
 }
 
-impl Sealed for ProgramAccountState {}
-
 impl IsInitialized for ProgramAccountState {
     fn is_initialized(&self) -> bool {
         self.is_initialized
     }
 }
-
-impl Pack for ProgramAccountState {
-    const LEN: usize = ACCOUNT_STATE_SPACE;
-
-    /// Store 'state' of account to its data area
-    fn pack_into_slice(&self, dst: &mut [u8]) {
-        sol_template_shared::pack_into_slice(self.is_initialized, &self.btree_storage, dst);
-    }
-
-    /// Retrieve 'state' of account from account data area
-    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        match sol_template_shared::unpack_from_slice(src) {
-            Ok((is_initialized, btree_map)) => Ok(ProgramAccountState {
-                is_initialized,
-                btree_storage: btree_map,
-            }),
-            Err(_) => Err(ProgramError::InvalidAccountData),
-        }
-    }
-}
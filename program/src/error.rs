@@ -2,6 +2,7 @@
 
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use sol_template_shared::StateError;
 use solana_program::{
     decode_error::DecodeError,
     program_error::{PrintProgramError, ProgramError},
@@ -18,6 +19,50 @@ pub enum SampleError {
     KeyAlreadyExists,
     InsufficientFundsForTransaction,
     UnknownError,
+    SourceMintMismatch,
+    DestinationMintMismatch,
+    MintDecimalsMismatch,
+    MemoProgramMissing,
+    AccountStorageFull,
+    InvalidAccountStateFlag,
+    AccountStateLengthOutOfRange,
+    AccountStateSerializationFailure,
+    InvalidAccountDiscriminator,
+    UnsupportedAccountVersion,
+    AccountTypeMismatch,
+    AccountNotWritable,
+    InvalidConfigAdmin,
+    TreasuryMismatch,
+    FeeMintMismatch,
+    InvalidFeeSchedule,
+    FeeRecipientMismatch,
+    InvalidMultisig,
+    NotMultisigMember,
+    MultisigApprovalRequired,
+    ProposalNotPending,
+    ProposalAlreadyVoted,
+    ProposalThresholdNotMet,
+    ProposalAccountMismatch,
+    SpendingLimitExceeded,
+    WithdrawalDelayRequired,
+    WithdrawalLocked,
+    NotGuardian,
+    InvalidGuardians,
+    RecoveryInProgress,
+    NoRecoveryPending,
+    RecoveryLocked,
+    SessionKeyExpired,
+    SessionKeyNotAllowed,
+    SessionBudgetExceeded,
+    InvalidSessionKey,
+    SubscriptionNotDue,
+    AllowanceExpired,
+    AllowanceExceeded,
+    InvalidAllowance,
+    InvalidSubscription,
+    SelfReferral,
+    InvalidWithdrawalDelay,
+    OwnerChanged,
 }
 
 impl From<SampleError> for ProgramError {
@@ -25,6 +70,19 @@ impl From<SampleError> for ProgramError {
         ProgramError::Custom(e as u32)
     }
 }
+impl From<StateError> for SampleError {
+    fn from(e: StateError) -> Self {
+        match e {
+            StateError::InvalidInitializedFlag(_) => SampleError::InvalidAccountStateFlag,
+            StateError::LengthOutOfRange { .. } => SampleError::AccountStateLengthOutOfRange,
+            StateError::BorshFailure(_) => SampleError::AccountStateSerializationFailure,
+            StateError::Overflow { .. } => SampleError::AccountStorageFull,
+            StateError::InvalidDiscriminator => SampleError::InvalidAccountDiscriminator,
+            StateError::UnsupportedVersion(_) => SampleError::UnsupportedAccountVersion,
+            StateError::UnexpectedAccountType(_) => SampleError::AccountTypeMismatch,
+        }
+    }
+}
 impl<T> DecodeError<T> for SampleError {
     fn type_of() -> &'static str {
         "SampleError"
@@ -43,6 +101,100 @@ impl fmt::Display for SampleError {
             SampleError::InsufficientFundsForTransaction => {
                 f.write_str("Not enough funds to process transaction")
             }
+            SampleError::SourceMintMismatch => f.write_str("Source token account mint mismatch"),
+            SampleError::DestinationMintMismatch => {
+                f.write_str("Destination token account mint mismatch")
+            }
+            SampleError::MintDecimalsMismatch => f.write_str("Mint decimals mismatch"),
+            SampleError::MemoProgramMissing => {
+                f.write_str("Destination requires a memo but no memo program was passed")
+            }
+            SampleError::AccountStorageFull => f.write_str("Account storage is full"),
+            SampleError::InvalidAccountStateFlag => {
+                f.write_str("Account state has an invalid initialization flag")
+            }
+            SampleError::AccountStateLengthOutOfRange => {
+                f.write_str("Account state length out of range")
+            }
+            SampleError::AccountStateSerializationFailure => {
+                f.write_str("Account state failed to serialize")
+            }
+            SampleError::InvalidAccountDiscriminator => {
+                f.write_str("Account has an unrecognized discriminator")
+            }
+            SampleError::UnsupportedAccountVersion => {
+                f.write_str("Account layout version is not supported")
+            }
+            SampleError::AccountTypeMismatch => f.write_str("Account is not of the expected type"),
+            SampleError::AccountNotWritable => f.write_str("Account is not writable"),
+            SampleError::InvalidConfigAdmin => {
+                f.write_str("Signer is not the admin of the program config")
+            }
+            SampleError::TreasuryMismatch => {
+                f.write_str("Service account is not the configured treasury")
+            }
+            SampleError::FeeMintMismatch => f.write_str("Mint is not the configured fee mint"),
+            SampleError::InvalidFeeSchedule => f.write_str("Fee schedule is out of range"),
+            SampleError::FeeRecipientMismatch => {
+                f.write_str("Account is not the configured fee share recipient")
+            }
+            SampleError::InvalidMultisig => {
+                f.write_str("Multisig members or threshold are out of range")
+            }
+            SampleError::NotMultisigMember => {
+                f.write_str("Signer is not a member of the multisig wallet")
+            }
+            SampleError::MultisigApprovalRequired => {
+                f.write_str("Multisig wallet transfers need an approved proposal")
+            }
+            SampleError::ProposalNotPending => f.write_str("Proposal is no longer pending"),
+            SampleError::ProposalAlreadyVoted => {
+                f.write_str("Member already voted on the proposal")
+            }
+            SampleError::ProposalThresholdNotMet => {
+                f.write_str("Proposal lacks the approvals of the threshold")
+            }
+            SampleError::ProposalAccountMismatch => {
+                f.write_str("Account is not the one named by the proposal")
+            }
+            SampleError::SpendingLimitExceeded => {
+                f.write_str("Transfer exceeds the spending limit of the wallet")
+            }
+            SampleError::WithdrawalDelayRequired => {
+                f.write_str("Transfers out of a time-locked wallet have to be queued")
+            }
+            SampleError::WithdrawalLocked => f.write_str("Withdrawal is still locked"),
+            SampleError::NotGuardian => {
+                f.write_str("Signer is neither the owner nor a guardian of the wallet")
+            }
+            SampleError::InvalidGuardians => f.write_str("Guardians are out of range"),
+            SampleError::RecoveryInProgress => {
+                f.write_str("Wallet already has a recovery in progress")
+            }
+            SampleError::NoRecoveryPending => f.write_str("Wallet has no recovery in progress"),
+            SampleError::RecoveryLocked => {
+                f.write_str("Recovery lacks approvals or is still in its veto period")
+            }
+            SampleError::SessionKeyExpired => f.write_str("Session key is expired"),
+            SampleError::SessionKeyNotAllowed => {
+                f.write_str("Session key is not allowed the instruction")
+            }
+            SampleError::SessionBudgetExceeded => {
+                f.write_str("Transfer exceeds the budget of the session key")
+            }
+            SampleError::InvalidSessionKey => f.write_str("Session key is out of range"),
+            SampleError::SubscriptionNotDue => f.write_str("Subscription is not due yet"),
+            SampleError::AllowanceExpired => f.write_str("Allowance is expired"),
+            SampleError::AllowanceExceeded => {
+                f.write_str("Transfer exceeds the allowance of the spender")
+            }
+            SampleError::InvalidAllowance => f.write_str("Allowance is out of range"),
+            SampleError::InvalidSubscription => {
+                f.write_str("Subscription schedule is out of range")
+            }
+            SampleError::SelfReferral => f.write_str("Fee payers can not refer themselves"),
+            SampleError::InvalidWithdrawalDelay => f.write_str("Withdrawal delay is out of range"),
+            SampleError::OwnerChanged => f.write_str("Set up by a previous owner of the wallet"),
         }
     }
 }
@@ -62,6 +214,96 @@ impl PrintProgramError for SampleError {
             SampleError::InsufficientFundsForTransaction => {
                 println!("Not enough funds to process transaction")
             }
+            SampleError::SourceMintMismatch => println!("Source token account mint mismatch"),
+            SampleError::DestinationMintMismatch => {
+                println!("Destination token account mint mismatch")
+            }
+            SampleError::MintDecimalsMismatch => println!("Mint decimals mismatch"),
+            SampleError::MemoProgramMissing => {
+                println!("Destination requires a memo but no memo program was passed")
+            }
+            SampleError::AccountStorageFull => println!("Account storage is full"),
+            SampleError::InvalidAccountStateFlag => {
+                println!("Account state has an invalid initialization flag")
+            }
+            SampleError::AccountStateLengthOutOfRange => {
+                println!("Account state length out of range")
+            }
+            SampleError::AccountStateSerializationFailure => {
+                println!("Account state failed to serialize")
+            }
+            SampleError::InvalidAccountDiscriminator => {
+                println!("Account has an unrecognized discriminator")
+            }
+            SampleError::UnsupportedAccountVersion => {
+                println!("Account layout version is not supported")
+            }
+            SampleError::AccountTypeMismatch => println!("Account is not of the expected type"),
+            SampleError::AccountNotWritable => println!("Account is not writable"),
+            SampleError::InvalidConfigAdmin => {
+                println!("Signer is not the admin of the program config")
+            }
+            SampleError::TreasuryMismatch => {
+                println!("Service account is not the configured treasury")
+            }
+            SampleError::FeeMintMismatch => println!("Mint is not the configured fee mint"),
+            SampleError::InvalidFeeSchedule => println!("Fee schedule is out of range"),
+            SampleError::FeeRecipientMismatch => {
+                println!("Account is not the configured fee share recipient")
+            }
+            SampleError::InvalidMultisig => {
+                println!("Multisig members or threshold are out of range")
+            }
+            SampleError::NotMultisigMember => {
+                println!("Signer is not a member of the multisig wallet")
+            }
+            SampleError::MultisigApprovalRequired => {
+                println!("Multisig wallet transfers need an approved proposal")
+            }
+            SampleError::ProposalNotPending => println!("Proposal is no longer pending"),
+            SampleError::ProposalAlreadyVoted => println!("Member already voted on the proposal"),
+            SampleError::ProposalThresholdNotMet => {
+                println!("Proposal lacks the approvals of the threshold")
+            }
+            SampleError::ProposalAccountMismatch => {
+                println!("Account is not the one named by the proposal")
+            }
+            SampleError::SpendingLimitExceeded => {
+                println!("Transfer exceeds the spending limit of the wallet")
+            }
+            SampleError::WithdrawalDelayRequired => {
+                println!("Transfers out of a time-locked wallet have to be queued")
+            }
+            SampleError::WithdrawalLocked => println!("Withdrawal is still locked"),
+            SampleError::NotGuardian => {
+                println!("Signer is neither the owner nor a guardian of the wallet")
+            }
+            SampleError::InvalidGuardians => println!("Guardians are out of range"),
+            SampleError::RecoveryInProgress => {
+                println!("Wallet already has a recovery in progress")
+            }
+            SampleError::NoRecoveryPending => println!("Wallet has no recovery in progress"),
+            SampleError::RecoveryLocked => {
+                println!("Recovery lacks approvals or is still in its veto period")
+            }
+            SampleError::SessionKeyExpired => println!("Session key is expired"),
+            SampleError::SessionKeyNotAllowed => {
+                println!("Session key is not allowed the instruction")
+            }
+            SampleError::SessionBudgetExceeded => {
+                println!("Transfer exceeds the budget of the session key")
+            }
+            SampleError::InvalidSessionKey => println!("Session key is out of range"),
+            SampleError::SubscriptionNotDue => println!("Subscription is not due yet"),
+            SampleError::AllowanceExpired => println!("Allowance is expired"),
+            SampleError::AllowanceExceeded => {
+                println!("Transfer exceeds the allowance of the spender")
+            }
+            SampleError::InvalidAllowance => println!("Allowance is out of range"),
+            SampleError::InvalidSubscription => println!("Subscription schedule is out of range"),
+            SampleError::SelfReferral => println!("Fee payers can not refer themselves"),
+            SampleError::InvalidWithdrawalDelay => println!("Withdrawal delay is out of range"),
+            SampleError::OwnerChanged => println!("Set up by a previous owner of the wallet"),
         }
     }
 }
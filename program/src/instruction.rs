@@ -1,37 +1,330 @@
+use crate::{
+    account_state::{FeeSchedule, ProposalAction, ProposedInstruction, SessionKey},
+    error::SampleError,
+};
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::{borsh::try_from_slice_unchecked, program_error::ProgramError};
+use solana_program::{
+    borsh::try_from_slice_unchecked, msg, program_error::ProgramError, pubkey::Pubkey,
+};
+
+/// What the processor requires of an account before dispatching
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AccountConstraints {
+    /// The account has to be owned by the program
+    pub program_owned: bool,
+    /// The account has to be writable
+    pub writable: bool,
+    /// The account has to sign the transaction
+    pub signer: bool,
+}
+
+impl AccountConstraints {
+    /// Any account, such as a program, a mint or a recipient
+    pub const ANY: Self = AccountConstraints {
+        program_owned: false,
+        writable: false,
+        signer: false,
+    };
+    /// An account the instruction credits or modifies
+    pub const WRITABLE: Self = AccountConstraints {
+        writable: true,
+        ..Self::ANY
+    };
+    /// A signer the instruction does not debit
+    pub const SIGNER: Self = AccountConstraints {
+        signer: true,
+        ..Self::ANY
+    };
+    /// A signer paying for the instruction
+    pub const PAYER: Self = AccountConstraints {
+        writable: true,
+        signer: true,
+        ..Self::ANY
+    };
+    /// A program account the instruction only reads
+    pub const PROGRAM_READONLY: Self = AccountConstraints {
+        program_owned: true,
+        ..Self::ANY
+    };
+    /// A program account the instruction modifies
+    pub const PROGRAM_WRITABLE: Self = AccountConstraints {
+        program_owned: true,
+        writable: true,
+        ..Self::ANY
+    };
+}
 
 #[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
 /// All custom program instructions
 pub enum ProgramInstruction {
     InitializeAccount,
-    WalletNew,
-    WalletTransferSpl,
-    WalletTransferLamports,
+    WalletNew(Option<u8>),
+    WalletTransferSpl(u64, u8, bool),
+    WalletTransferLamports(u64),
     MintToAccount(String, String),
     TransferBetweenAccounts(String),
     BurnFromAccount(String),
     MintToAccountWithFee(String, String),
     TransferBetweenAccountsWithFee(String),
     BurnFromAccountWithFee(String),
+    MigrateAccount,
+    InitializeConfig(FeeSchedule),
+    UpdateFees(FeeSchedule),
+    RegisterReferrer,
+    ClaimReferralRewards,
+    SetFeeDiscount(Pubkey, u8),
+    WalletNewMultisig(Option<u8>, Vec<Pubkey>, u8),
+    ProposeTransfer(ProposalAction),
+    Approve,
+    Reject,
+    Execute,
+    ProposeInstruction(ProposedInstruction),
+    SetSpendingLimit(Option<Pubkey>, u64, i64),
+    SetGuardians(Vec<Pubkey>, u8),
+    SetWithdrawalDelay(i64, u64),
+    QueueWithdrawal(ProposalAction),
+    ExecuteWithdrawal,
+    CancelWithdrawal,
+    InitiateRecovery(Pubkey),
+    ApproveRecovery,
+    FinalizeRecovery,
+    VetoRecovery,
+    AddSessionKey(SessionKey),
+    RevokeSessionKey(Pubkey),
+    CreateSubscription(ProposalAction, i64, i64),
+    CollectSubscription,
+    CancelSubscription,
+    ApproveAllowance(Pubkey, Pubkey, u64, i64),
+    RevokeAllowance(Pubkey, Pubkey),
+    TransferFrom(u64, u8),
 }
 
 impl ProgramInstruction {
     /// Unpack inbound buffer to associated Instruction
     /// The expected format for input is a Borsh serialized vector
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        let payload = try_from_slice_unchecked::<ProgramInstruction>(input).unwrap();
+        let payload = try_from_slice_unchecked::<ProgramInstruction>(input).map_err(|error| {
+            msg!("Fail: Unpacking instruction: {}", error);
+            SampleError::DeserializationFailure
+        })?;
         match payload {
             ProgramInstruction::InitializeAccount => Ok(payload),
-            ProgramInstruction::WalletNew => Ok(payload),
-            ProgramInstruction::WalletTransferSpl => Ok(payload),
-            ProgramInstruction::WalletTransferLamports => Ok(payload),
+            ProgramInstruction::WalletNew(_) => Ok(payload),
+            ProgramInstruction::WalletTransferSpl(_, _, _) => Ok(payload),
+            ProgramInstruction::WalletTransferLamports(_) => Ok(payload),
             ProgramInstruction::MintToAccount(_, _) => Ok(payload),
             ProgramInstruction::TransferBetweenAccounts(_) => Ok(payload),
             ProgramInstruction::BurnFromAccount(_) => Ok(payload),
             ProgramInstruction::MintToAccountWithFee(_, _) => Ok(payload),
             ProgramInstruction::TransferBetweenAccountsWithFee(_) => Ok(payload),
             ProgramInstruction::BurnFromAccountWithFee(_) => Ok(payload),
+            ProgramInstruction::MigrateAccount => Ok(payload),
+            ProgramInstruction::InitializeConfig(_) => Ok(payload),
+            ProgramInstruction::UpdateFees(_) => Ok(payload),
+            ProgramInstruction::RegisterReferrer => Ok(payload),
+            ProgramInstruction::ClaimReferralRewards => Ok(payload),
+            ProgramInstruction::SetFeeDiscount(_, _) => Ok(payload),
+            ProgramInstruction::WalletNewMultisig(_, _, _) => Ok(payload),
+            ProgramInstruction::ProposeTransfer(_) => Ok(payload),
+            ProgramInstruction::Approve => Ok(payload),
+            ProgramInstruction::Reject => Ok(payload),
+            ProgramInstruction::Execute => Ok(payload),
+            ProgramInstruction::ProposeInstruction(_) => Ok(payload),
+            ProgramInstruction::SetSpendingLimit(_, _, _) => Ok(payload),
+            ProgramInstruction::SetGuardians(_, _) => Ok(payload),
+            ProgramInstruction::SetWithdrawalDelay(_, _) => Ok(payload),
+            ProgramInstruction::QueueWithdrawal(_) => Ok(payload),
+            ProgramInstruction::ExecuteWithdrawal => Ok(payload),
+            ProgramInstruction::CancelWithdrawal => Ok(payload),
+            ProgramInstruction::InitiateRecovery(_) => Ok(payload),
+            ProgramInstruction::ApproveRecovery => Ok(payload),
+            ProgramInstruction::FinalizeRecovery => Ok(payload),
+            ProgramInstruction::VetoRecovery => Ok(payload),
+            ProgramInstruction::AddSessionKey(_) => Ok(payload),
+            ProgramInstruction::RevokeSessionKey(_) => Ok(payload),
+            ProgramInstruction::CreateSubscription(_, _, _) => Ok(payload),
+            ProgramInstruction::CollectSubscription => Ok(payload),
+            ProgramInstruction::CancelSubscription => Ok(payload),
+            ProgramInstruction::ApproveAllowance(_, _, _, _) => Ok(payload),
+            ProgramInstruction::RevokeAllowance(_, _) => Ok(payload),
+            ProgramInstruction::TransferFrom(_, _) => Ok(payload),
+        }
+    }
+
+    /// The index of the variant, the first byte of the packed instruction,
+    /// by which session keys are allowed instructions
+    pub fn tag(&self) -> Result<u8, ProgramError> {
+        match self.try_to_vec() {
+            Ok(data) if !data.is_empty() => Ok(data[0]),
+            _ => Err(SampleError::DeserializationFailure.into()),
+        }
+    }
+
+    /// The constraints on the leading accounts of the instruction, in order.
+    /// Optional trailing accounts, like the fee token accounts, fee share
+    /// recipients and referral account of the `*WithFee` instructions, or the
+    /// accounts of the action a proposal executes, are left for the handler
+    /// to check.
+    pub fn account_constraints(&self) -> Vec<AccountConstraints> {
+        use AccountConstraints as C;
+        match self {
+            ProgramInstruction::InitializeAccount => vec![C::PROGRAM_WRITABLE],
+            // owner, wallet, system program
+            ProgramInstruction::WalletNew(_) | ProgramInstruction::WalletNewMultisig(_, _, _) => {
+                vec![C::PAYER, C::WRITABLE, C::ANY]
+            }
+            // wallet, owner or spender, source, mint, destination, authority,
+            // token program, config, treasury token account and when creating
+            // the destination recipient, associated token program and system
+            // program
+            ProgramInstruction::WalletTransferSpl(_, _, false)
+            | ProgramInstruction::TransferFrom(_, _) => vec![
+                C::PROGRAM_WRITABLE,
+                C::SIGNER,
+                C::WRITABLE,
+                C::ANY,
+                C::WRITABLE,
+                C::ANY,
+                C::ANY,
+                C::PROGRAM_READONLY,
+                C::WRITABLE,
+            ],
+            ProgramInstruction::WalletTransferSpl(_, _, true) => vec![
+                C::PROGRAM_WRITABLE,
+                C::SIGNER,
+                C::WRITABLE,
+                C::ANY,
+                C::WRITABLE,
+                C::WRITABLE,
+                C::ANY,
+                C::PROGRAM_READONLY,
+                C::WRITABLE,
+                C::ANY,
+                C::ANY,
+                C::ANY,
+            ],
+            // wallet, owner, destination, config, treasury
+            ProgramInstruction::WalletTransferLamports(_) => vec![
+                C::PROGRAM_WRITABLE,
+                C::SIGNER,
+                C::WRITABLE,
+                C::PROGRAM_READONLY,
+                C::WRITABLE,
+            ],
+            // program account, payer, system program
+            ProgramInstruction::MintToAccount(_, _) | ProgramInstruction::MigrateAccount => {
+                vec![C::PROGRAM_WRITABLE, C::PAYER, C::ANY]
+            }
+            // from and to program accounts, payer, system program
+            ProgramInstruction::TransferBetweenAccounts(_) => {
+                vec![C::PROGRAM_WRITABLE, C::PROGRAM_WRITABLE, C::PAYER, C::ANY]
+            }
+            ProgramInstruction::BurnFromAccount(_) => vec![C::PROGRAM_WRITABLE],
+            // program account, service account, config, fee tiers, payer,
+            // system program
+            ProgramInstruction::MintToAccountWithFee(_, _) => vec![
+                C::PROGRAM_WRITABLE,
+                C::PROGRAM_WRITABLE,
+                C::PROGRAM_READONLY,
+                C::ANY,
+                C::PAYER,
+                C::ANY,
+            ],
+            // from and to program accounts, service account, config, fee
+            // tiers, payer, system program
+            ProgramInstruction::TransferBetweenAccountsWithFee(_) => vec![
+                C::PROGRAM_WRITABLE,
+                C::PROGRAM_WRITABLE,
+                C::PROGRAM_WRITABLE,
+                C::PROGRAM_READONLY,
+                C::ANY,
+                C::PAYER,
+                C::ANY,
+            ],
+            // program account, service account, config, fee tiers
+            ProgramInstruction::BurnFromAccountWithFee(_) => vec![
+                C::PROGRAM_WRITABLE,
+                C::PROGRAM_WRITABLE,
+                C::PROGRAM_READONLY,
+                C::ANY,
+            ],
+            // admin, config, fee recipient, program data, system program
+            ProgramInstruction::InitializeConfig(_) => {
+                vec![C::PAYER, C::WRITABLE, C::ANY, C::ANY, C::ANY]
+            }
+            // admin, config, system program
+            ProgramInstruction::UpdateFees(_) => vec![C::PAYER, C::PROGRAM_WRITABLE, C::ANY],
+            // referrer, referral account, system program
+            ProgramInstruction::RegisterReferrer => vec![C::PAYER, C::WRITABLE, C::ANY],
+            // referrer, referral account, destination and optionally the
+            // referral token account, mint, destination token account and
+            // token program
+            ProgramInstruction::ClaimReferralRewards => {
+                vec![C::SIGNER, C::PROGRAM_WRITABLE, C::WRITABLE]
+            }
+            // admin, config, fee tiers, system program
+            ProgramInstruction::SetFeeDiscount(_, _) => {
+                vec![C::PAYER, C::PROGRAM_READONLY, C::WRITABLE, C::ANY]
+            }
+            // wallet, member, proposal, system program
+            ProgramInstruction::ProposeTransfer(_) | ProgramInstruction::ProposeInstruction(_) => {
+                vec![C::PROGRAM_WRITABLE, C::PAYER, C::WRITABLE, C::ANY]
+            }
+            // wallet, member, proposal
+            ProgramInstruction::Approve | ProgramInstruction::Reject => {
+                vec![C::PROGRAM_READONLY, C::SIGNER, C::PROGRAM_WRITABLE]
+            }
+            // wallet, member, proposal and the accounts of the transfer, as
+            // for `WalletTransferLamports` or `WalletTransferSpl` from the
+            // destination or the source on, or the invoked program and the
+            // accounts of the proposed instruction
+            ProgramInstruction::Execute => {
+                vec![C::PROGRAM_WRITABLE, C::SIGNER, C::PROGRAM_WRITABLE]
+            }
+            // wallet, owner, system program
+            ProgramInstruction::SetSpendingLimit(_, _, _)
+            | ProgramInstruction::SetGuardians(_, _)
+            | ProgramInstruction::SetWithdrawalDelay(_, _)
+            | ProgramInstruction::AddSessionKey(_)
+            | ProgramInstruction::ApproveAllowance(_, _, _, _) => {
+                vec![C::PROGRAM_WRITABLE, C::PAYER, C::ANY]
+            }
+            // wallet, owner, pending withdrawal or subscription, system program
+            ProgramInstruction::QueueWithdrawal(_)
+            | ProgramInstruction::CreateSubscription(_, _, _) => {
+                vec![C::PROGRAM_WRITABLE, C::PAYER, C::WRITABLE, C::ANY]
+            }
+            // wallet, owner or session key, pending withdrawal, owner and the
+            // accounts of the transfer as for `Execute`
+            ProgramInstruction::ExecuteWithdrawal => vec![
+                C::PROGRAM_WRITABLE,
+                C::SIGNER,
+                C::PROGRAM_WRITABLE,
+                C::WRITABLE,
+            ],
+            // wallet, owner or guardian, pending withdrawal or subscription,
+            // owner
+            ProgramInstruction::CancelWithdrawal | ProgramInstruction::CancelSubscription => vec![
+                C::PROGRAM_READONLY,
+                C::SIGNER,
+                C::PROGRAM_WRITABLE,
+                C::WRITABLE,
+            ],
+            // wallet, guardian, system program
+            ProgramInstruction::InitiateRecovery(_) | ProgramInstruction::ApproveRecovery => {
+                vec![C::PROGRAM_WRITABLE, C::PAYER, C::ANY]
+            }
+            // wallet
+            ProgramInstruction::FinalizeRecovery => vec![C::PROGRAM_WRITABLE],
+            // wallet, owner
+            ProgramInstruction::VetoRecovery
+            | ProgramInstruction::RevokeSessionKey(_)
+            | ProgramInstruction::RevokeAllowance(_, _) => vec![C::PROGRAM_WRITABLE, C::SIGNER],
+            // wallet, subscription and the accounts of the transfer as for
+            // `Execute`
+            ProgramInstruction::CollectSubscription => {
+                vec![C::PROGRAM_WRITABLE, C::PROGRAM_WRITABLE]
+            }
         }
     }
 }
@@ -1,37 +1,76 @@
 use crate::{
-    account_state::ProgramAccountState, error::SampleError, instruction::ProgramInstruction,
+    account_state::{
+        find_config_address, find_fee_tiers_address, find_proposal_address, find_referral_address,
+        find_subscription_address, find_wallet_address, find_wallet_authority_address,
+        find_withdrawal_address, AccountType, Allowance, FeeSchedule, FeeTiers, Multisig,
+        PendingRecovery, PendingWithdrawal, ProgramAccountState, ProgramConfig, Proposal,
+        ProposalAction, ProposalStatus, ProposedInstruction, ReferralAccount, SessionKey,
+        SpendingLimit, Subscription, WithdrawalPolicyChange, AUTHORITY_SEED, CONFIG_SEED,
+        FEE_EXEMPT_DISCOUNT, FEE_TIERS_SEED, MAX_ALLOWANCES, MAX_FEE_SHARES, MAX_GUARDIANS,
+        MAX_MULTISIG_MEMBERS, MAX_SESSION_KEYS, MAX_WITHDRAWAL_DELAY, PROPOSAL_SEED,
+        REFERRAL_SEED, SUBSCRIPTION_SEED, WALLET_SEED, WITHDRAWAL_SEED,
+    },
+    error::SampleError,
+    instruction::{AccountConstraints, ProgramInstruction},
 };
+use sol_template_shared::LAYOUT_VERSION;
+use std::slice::Iter;
 use {
     solana_program::{
         account_info::{next_account_info, AccountInfo},
-        entrypoint::ProgramResult,
+        bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+        clock::Clock,
+        entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
         msg,
-        program::invoke_signed,
+        program::{invoke, invoke_signed},
         program_error::ProgramError,
-        program_pack::{IsInitialized, Pack},
+        program_pack::IsInitialized,
         pubkey::Pubkey,
+        rent::Rent,
+        system_instruction, system_program,
+        sysvar::Sysvar,
+    },
+    spl_associated_token_account::{
+        get_associated_token_address_with_program_id,
+        instruction::create_associated_token_account_idempotent,
     },
-    spl_token::{
+    spl_token_2022::{
+        check_spl_token_program_account,
+        extension::{
+            memo_transfer::MemoTransfer,
+            transfer_fee::{instruction::transfer_checked_with_fee, TransferFeeConfig},
+            BaseStateWithExtensions, ExtensionType, StateWithExtensions,
+        },
         instruction::transfer_checked,
         state::{Account, Mint},
     },
 };
 
 /// Service fees debited from participating accounts and
-/// credited to a 'service' account
+/// credited to a 'service' account, priced by the program config
 enum SampleServiceFees {
-    Minting = 10,
-    Transfering = 30,
-    Burning = 15,
-}
-
-/// Checks each tracking account to confirm it is owned by our program
-/// This function assumes that the program account is always the last
-/// in the array
-fn check_account_ownership(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
-    // Accounts must be owned by the program.
-    for account in accounts.iter().take(accounts.len() - 1) {
-        if account.owner != program_id {
+    Minting,
+    Transfering,
+    Burning,
+}
+
+/// Checks the accounts against the constraints the instruction declares
+/// for them, accounts past the constraints are left to the handler
+fn check_accounts(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    constraints: &[AccountConstraints],
+) -> ProgramResult {
+    if accounts.len() < constraints.len() {
+        msg!(
+            "Fail: The instruction needs {} accounts and got {}.",
+            constraints.len(),
+            accounts.len()
+        );
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
+    for (account, constraint) in accounts.iter().zip(constraints) {
+        if constraint.program_owned && account.owner != program_id {
             msg!(
                 "Fail: The tracking account owner is {} and it should be {}.",
                 account.owner,
@@ -39,251 +78,2648 @@ fn check_account_ownership(program_id: &Pubkey, accounts: &[AccountInfo]) -> Pro
             );
             return Err(ProgramError::IncorrectProgramId);
         }
+        if constraint.writable && !account.is_writable {
+            msg!("Fail: The account {} should be writable.", account.key);
+            return Err(SampleError::AccountNotWritable.into());
+        }
+        if constraint.signer && !account.is_signer {
+            msg!("Fail: The account {} should sign.", account.key);
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the program config, which has to be at the config address
+fn load_config(
+    program_id: &Pubkey,
+    config_info: &AccountInfo,
+) -> Result<ProgramConfig, ProgramError> {
+    let (expected_config, _) = find_config_address(program_id);
+    if expected_config != *config_info.key {
+        msg!(
+            "Fail: The config account is {} and it should be {}.",
+            config_info.key,
+            expected_config
+        );
+        return Err(ProgramError::InvalidSeeds);
+    }
+    ProgramConfig::unpack_account(&config_info.try_borrow_data()?)
+}
+
+/// Reads the fee tiers, which have to be at the fee tiers address. Until the
+/// admin sets a first discount the account does not exist and nobody gets one.
+fn load_fee_tiers(
+    program_id: &Pubkey,
+    fee_tiers_info: &AccountInfo,
+) -> Result<FeeTiers, ProgramError> {
+    let (expected_fee_tiers, _) = find_fee_tiers_address(program_id);
+    if expected_fee_tiers != *fee_tiers_info.key {
+        msg!(
+            "Fail: The fee tiers account is {} and it should be {}.",
+            fee_tiers_info.key,
+            expected_fee_tiers
+        );
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if fee_tiers_info.data_is_empty() {
+        return Ok(FeeTiers::default());
+    }
+    if fee_tiers_info.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    FeeTiers::unpack_account(&fee_tiers_info.try_borrow_data()?)
+}
+
+/// Basis points making up the whole of an amount
+const BASIS_POINTS: u128 = 10_000;
+
+/// Checks that the fee schedule keeps its fees within bounds
+fn check_fee_schedule(fees: &FeeSchedule) -> ProgramResult {
+    let share_basis_points = fees
+        .shares
+        .iter()
+        .map(|share| u128::from(share.basis_points))
+        .sum::<u128>();
+    if u128::from(fees.transfer_basis_points) > BASIS_POINTS
+        || fees.transfer_fee_min > fees.transfer_fee_max
+        || fees.shares.len() > MAX_FEE_SHARES
+        || share_basis_points + u128::from(fees.referral_basis_points) > BASIS_POINTS
+    {
+        msg!("Fail: The fee schedule {:?} is out of range.", fees);
+        return Err(SampleError::InvalidFeeSchedule.into());
+    }
+    Ok(())
+}
+
+/// Checks that the account is the treasury of the program config
+fn check_treasury(config: &ProgramConfig, treasury_info: &AccountInfo) -> ProgramResult {
+    if config.fee_recipient != *treasury_info.key {
+        msg!(
+            "Fail: The service account is {} and the treasury is {}.",
+            treasury_info.key,
+            config.fee_recipient
+        );
+        return Err(SampleError::TreasuryMismatch.into());
+    }
+    Ok(())
+}
+
+/// Returns the owner of the token account
+fn token_account_owner(token_info: &AccountInfo) -> Result<Pubkey, ProgramError> {
+    let token_data = token_info.try_borrow_data()?;
+    Ok(StateWithExtensions::<Account>::unpack(&token_data)?.base.owner)
+}
+
+/// Checks that the token account is held by the treasury of the program config
+fn check_treasury_token_account(
+    config: &ProgramConfig,
+    treasury_token_info: &AccountInfo,
+) -> ProgramResult {
+    let treasury_owner = token_account_owner(treasury_token_info)?;
+    if treasury_owner != config.fee_recipient {
+        msg!(
+            "Fail: The fee token account is held by {} and the treasury is {}.",
+            treasury_owner,
+            config.fee_recipient
+        );
+        return Err(SampleError::TreasuryMismatch.into());
+    }
+    Ok(())
+}
+
+/// Computes the basis-points fee taken from a wallet transfer, kept within
+/// the minimum and maximum of the fee schedule
+fn wallet_transfer_fee(fees: &FeeSchedule, amount: u64) -> Result<u64, ProgramError> {
+    let fee = basis_points_share(amount, fees.transfer_basis_points)?
+        .max(fees.transfer_fee_min)
+        .min(fees.transfer_fee_max);
+    if fee > amount {
+        msg!("Fail: The transfer of {} does not cover the fee of {}.", amount, fee);
+        return Err(SampleError::InsufficientFundsForTransaction.into());
+    }
+    msg!(
+        "Wallet transfer fee of {} on {} ({} basis points, min {}, max {})",
+        fee,
+        amount,
+        fees.transfer_basis_points,
+        fees.transfer_fee_min,
+        fees.transfer_fee_max
+    );
+    Ok(fee)
+}
+
+/// Token accounts paying the service fees when the fee schedule has a fee
+/// mint, trailing the accounts of the `*WithFee` instructions
+struct FeeTokenAccounts<'a, 'b> {
+    /// Token account of the fee mint the fees are taken from
+    source: &'b AccountInfo<'a>,
+    /// The fee mint
+    mint: &'b AccountInfo<'a>,
+    /// Token account of the fee mint held by the treasury
+    treasury: &'b AccountInfo<'a>,
+    /// Token program of the fee mint
+    token_program: &'b AccountInfo<'a>,
+    /// Owner of the source token account, signing for the fees
+    owner: &'b AccountInfo<'a>,
+}
+
+/// Accounts the service fees of the `*WithFee` instructions go to, and come
+/// from when paid in tokens
+struct FeeAccounts<'a, 'b> {
+    /// The treasury, which takes what the fee shares leave
+    treasury: &'b AccountInfo<'a>,
+    /// Token accounts paying the fees when the fee schedule has a fee mint
+    tokens: Option<FeeTokenAccounts<'a, 'b>>,
+    /// Recipients of the fee shares in order, or their token accounts of the
+    /// fee mint
+    shares: Vec<&'b AccountInfo<'a>>,
+    /// Referral account earning the referral share, if one is passed along
+    referral: Option<&'b AccountInfo<'a>>,
+    /// Referrer of the referral account
+    referrer: Option<Pubkey>,
+    /// Token account of the fee mint held by the referral account
+    referral_token: Option<&'b AccountInfo<'a>>,
+}
+
+impl<'a, 'b> FeeAccounts<'a, 'b> {
+    /// Takes the fee token accounts, when the fee schedule charges in tokens,
+    /// followed by the fee share recipients and the optional referral account
+    /// with its token account from the accounts iterator. Anything passed
+    /// after the fee share recipients has to be a referral account.
+    fn next<I: Iterator<Item = &'b AccountInfo<'a>>>(
+        program_id: &Pubkey,
+        config: &ProgramConfig,
+        treasury: &'b AccountInfo<'a>,
+        account_info_iter: &mut I,
+    ) -> Result<Self, ProgramError> {
+        let tokens = match config.fees.mint {
+            Some(_) => Some(FeeTokenAccounts {
+                source: next_account_info(account_info_iter)?,
+                mint: next_account_info(account_info_iter)?,
+                treasury: next_account_info(account_info_iter)?,
+                token_program: next_account_info(account_info_iter)?,
+                owner: next_account_info(account_info_iter)?,
+            }),
+            None => None,
+        };
+        let shares = config
+            .fees
+            .shares
+            .iter()
+            .map(|_| next_account_info(account_info_iter))
+            .collect::<Result<_, _>>()?;
+        let referral = next_account_info(account_info_iter).ok();
+        let referrer = match referral {
+            Some(referral_info) => Some(check_referral(program_id, referral_info)?.referrer),
+            None => None,
+        };
+        let referral_token = match (referral, &tokens) {
+            (Some(_), Some(_)) => Some(next_account_info(account_info_iter)?),
+            _ => None,
+        };
+        Ok(FeeAccounts {
+            treasury,
+            tokens,
+            shares,
+            referral,
+            referrer,
+            referral_token,
+        })
+    }
+
+    /// Checks that the referrer is not the one paying the fee, who would
+    /// otherwise take the referral share as a discount
+    fn check_not_self_referred(&self, payer: &Pubkey) -> ProgramResult {
+        if self.referrer.as_ref() == Some(payer) {
+            msg!("Fail: {} can not refer itself.", payer);
+            return Err(SampleError::SelfReferral.into());
+        }
+        Ok(())
+    }
+}
+
+/// Computes the basis points share of an amount, rounding down
+fn basis_points_share(amount: u64, basis_points: u16) -> Result<u64, ProgramError> {
+    u128::from(amount)
+        .checked_mul(u128::from(basis_points))
+        .and_then(|share| share.checked_div(BASIS_POINTS))
+        .and_then(|share| u64::try_from(share).ok())
+        .ok_or(ProgramError::InvalidArgument)
+}
+
+/// Splits the fee along the fee shares and, when a referrer is along, the
+/// referral share. Returns what is left for the treasury, rounding remainder
+/// included, the amount of each share and the referral share.
+fn split_fee(
+    fees: &FeeSchedule,
+    amount: u64,
+    referred: bool,
+) -> Result<(u64, Vec<u64>, u64), ProgramError> {
+    let share_amounts = fees
+        .shares
+        .iter()
+        .map(|share| basis_points_share(amount, share.basis_points))
+        .collect::<Result<Vec<u64>, _>>()?;
+    let referral_amount = match referred {
+        true => basis_points_share(amount, fees.referral_basis_points)?,
+        false => 0,
+    };
+    let treasury_amount = share_amounts
+        .iter()
+        .chain([&referral_amount])
+        .try_fold(amount, |rest, share_amount| rest.checked_sub(*share_amount))
+        .ok_or(ProgramError::InvalidArgument)?;
+    Ok((treasury_amount, share_amounts, referral_amount))
+}
+
+/// Checks that the account is a referral account at the address of its
+/// referrer, returning its state
+fn check_referral(
+    program_id: &Pubkey,
+    referral_info: &AccountInfo,
+) -> Result<ReferralAccount, ProgramError> {
+    if referral_info.owner != program_id {
+        msg!("Fail: The account {} is not a referral account.", referral_info.key);
+        return Err(SampleError::AccountTypeMismatch.into());
+    }
+    let referral = ReferralAccount::unpack_account(&referral_info.try_borrow_data()?)?;
+    let (expected_referral, _) = find_referral_address(program_id, &referral.referrer);
+    if expected_referral != *referral_info.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok(referral)
+}
+
+/// Extracts the configured service fee from the users program account and
+/// credits the service account, which has to be the configured treasury,
+/// the fee share recipients and the referral account, if any. With a fee
+/// mint the fee is paid in tokens to their token accounts instead.
+fn charge_service_fee(
+    config: &ProgramConfig,
+    fee_tiers: &FeeTiers,
+    service: SampleServiceFees,
+    program_account: &AccountInfo,
+    fee_accounts: &FeeAccounts,
+) -> ProgramResult {
+    check_treasury(config, fee_accounts.treasury)?;
+    let amount = match service {
+        SampleServiceFees::Minting => config.fees.minting,
+        SampleServiceFees::Transfering => config.fees.transferring,
+        SampleServiceFees::Burning => config.fees.burning,
+    };
+    // Discounts go to whoever pays, the token owner when paying in tokens
+    let payer = match &fee_accounts.tokens {
+        Some(fee_token_accounts) => fee_token_accounts.owner.key,
+        None => program_account.key,
+    };
+    fee_accounts.check_not_self_referred(payer)?;
+    let discount = fee_tiers.discount(payer);
+    if discount > 0 {
+        msg!("Fee discount of {}% for {}", discount, payer);
+    }
+    let amount = u128::from(amount)
+        .checked_mul(u128::from(FEE_EXEMPT_DISCOUNT.saturating_sub(discount)))
+        .and_then(|amount| amount.checked_div(u128::from(FEE_EXEMPT_DISCOUNT)))
+        .and_then(|amount| u64::try_from(amount).ok())
+        .ok_or(ProgramError::InvalidArgument)?;
+    let (treasury_amount, share_amounts, referral_amount) =
+        split_fee(&config.fees, amount, fee_accounts.referral.is_some())?;
+    if let Some(fee_token_accounts) = &fee_accounts.tokens {
+        return charge_token_fee(
+            config,
+            fee_token_accounts,
+            fee_accounts,
+            treasury_amount,
+            &share_amounts,
+            referral_amount,
+        );
+    }
+    // Debit user, unless tracking can not afford transaction fee, and
+    // credit service and the fee shares
+    let program_lamports = program_account
+        .lamports()
+        .checked_sub(amount)
+        .ok_or(SampleError::InsufficientFundsForTransaction)?;
+    **program_account.try_borrow_mut_lamports()? = program_lamports;
+    credit_lamports(fee_accounts.treasury, treasury_amount)?;
+    for ((share, share_info), share_amount) in config
+        .fees
+        .shares
+        .iter()
+        .zip(&fee_accounts.shares)
+        .zip(share_amounts)
+    {
+        if share.recipient != *share_info.key {
+            msg!("Fail: {} is not the fee share recipient {}.", share_info.key, share.recipient);
+            return Err(SampleError::FeeRecipientMismatch.into());
+        }
+        msg!("Fee share of {} lamports to {}", share_amount, share_info.key);
+        credit_lamports(share_info, share_amount)?;
+    }
+    if let Some(referral_info) = fee_accounts.referral {
+        msg!("Referral share of {} lamports to {}", referral_amount, referral_info.key);
+        credit_lamports(referral_info, referral_amount)?;
+    }
+    Ok(())
+}
+
+/// Credits lamports to an account, failing instead of overflowing its balance
+fn credit_lamports(account_info: &AccountInfo, amount: u64) -> ProgramResult {
+    let lamports = account_info
+        .lamports()
+        .checked_add(amount)
+        .ok_or(ProgramError::InvalidArgument)?;
+    **account_info.try_borrow_mut_lamports()? = lamports;
+    Ok(())
+}
+
+/// Transfers the service fee in tokens of the fee mint from the payers token
+/// account to the token accounts of the treasury, the fee shares and the
+/// referral account
+fn charge_token_fee<'a>(
+    config: &ProgramConfig,
+    fee_token_accounts: &FeeTokenAccounts<'a, '_>,
+    fee_accounts: &FeeAccounts<'a, '_>,
+    treasury_amount: u64,
+    share_amounts: &[u64],
+    referral_amount: u64,
+) -> ProgramResult {
+    if config.fees.mint.as_ref() != Some(fee_token_accounts.mint.key) {
+        msg!("Fail: {} is not the fee mint.", fee_token_accounts.mint.key);
+        return Err(SampleError::FeeMintMismatch.into());
+    }
+    check_treasury_token_account(config, fee_token_accounts.treasury)?;
+    let mint_data = fee_token_accounts.mint.try_borrow_data()?;
+    let decimals = StateWithExtensions::<Mint>::unpack(&mint_data)?.base.decimals;
+    drop(mint_data);
+
+    let mut payments = vec![(fee_token_accounts.treasury, treasury_amount)];
+    for ((share, share_info), share_amount) in config
+        .fees
+        .shares
+        .iter()
+        .zip(&fee_accounts.shares)
+        .zip(share_amounts)
+    {
+        let share_owner = token_account_owner(share_info)?;
+        if share.recipient != share_owner {
+            msg!(
+                "Fail: The fee token account is held by {} and not by {}.",
+                share_owner,
+                share.recipient
+            );
+            return Err(SampleError::FeeRecipientMismatch.into());
+        }
+        payments.push((*share_info, *share_amount));
+    }
+    if let (Some(referral_info), Some(referral_token_info)) =
+        (fee_accounts.referral, fee_accounts.referral_token)
+    {
+        if token_account_owner(referral_token_info)? != *referral_info.key {
+            msg!("Fail: The referral token account is not held by {}.", referral_info.key);
+            return Err(SampleError::FeeRecipientMismatch.into());
+        }
+        payments.push((referral_token_info, referral_amount));
+    }
+    for (destination_info, amount) in payments.into_iter().filter(|(_, amount)| *amount > 0) {
+        msg!("Charging a service fee of {} tokens to {}", amount, destination_info.key);
+        transfer_tokens_signed(
+            fee_token_accounts.source,
+            fee_token_accounts.mint,
+            destination_info,
+            fee_token_accounts.owner,
+            fee_token_accounts.token_program,
+            None,
+            amount,
+            decimals,
+            &[], // the owner signs the transaction
+        )?;
+    }
+    Ok(())
+}
+
+/// Moves lamports out of a program owned wallet, or any other program owned
+/// account, which can not go below its rent exempt minimum
+fn debit_wallet_lamports(
+    wallet_info: &AccountInfo,
+    destination_info: &AccountInfo,
+    amount: u64,
+) -> ProgramResult {
+    let rent_exempt_minimum = Rent::get()?.minimum_balance(wallet_info.data_len());
+    let wallet_lamports = wallet_info
+        .lamports()
+        .checked_sub(amount)
+        .filter(|lamports| *lamports >= rent_exempt_minimum)
+        .ok_or(SampleError::InsufficientFundsForTransaction)?;
+    let destination_lamports = destination_info
+        .lamports()
+        .checked_add(amount)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    **wallet_info.try_borrow_mut_lamports()? = wallet_lamports;
+    **destination_info.try_borrow_mut_lamports()? = destination_lamports;
+    Ok(())
+}
+
+/// Checks that the program account holds the type of state the
+/// instruction expects
+fn check_account_type(
+    account_info: &AccountInfo,
+    account_state: &ProgramAccountState,
+    expected: AccountType,
+) -> ProgramResult {
+    if account_state.account_type() != expected {
+        msg!(
+            "Fail: The account {} is a {} and it should be a {}.",
+            account_info.key,
+            account_state.account_type(),
+            expected
+        );
+        return Err(SampleError::AccountTypeMismatch.into());
+    }
+    Ok(())
+}
+
+/// Grows the account to the new length, topping up its rent exemption
+/// from the payer first
+fn grow_account<'a>(
+    account_info: &AccountInfo<'a>,
+    payer_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    new_len: usize,
+) -> ProgramResult {
+    // Accounts can only grow by so much within one instruction
+    if new_len.saturating_sub(account_info.data_len()) > MAX_PERMITTED_DATA_INCREASE {
+        return Err(SampleError::AccountStorageFull.into());
+    }
+    let top_up = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(account_info.lamports());
+    if top_up > 0 {
+        invoke(
+            &system_instruction::transfer(payer_info.key, account_info.key, top_up),
+            &[
+                payer_info.clone(),
+                account_info.clone(),
+                system_program_info.clone(),
+            ],
+        )?;
+    }
+    msg!("Growing account {} to {} bytes", account_info.key, new_len);
+    account_info.realloc(new_len, false)
+}
+
+/// Resizes the account to the new length, growing it like `grow_account`
+/// or shrinking it and leaving its lamports be
+fn resize_account<'a>(
+    account_info: &AccountInfo<'a>,
+    payer_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    new_len: usize,
+) -> ProgramResult {
+    if new_len > account_info.data_len() {
+        grow_account(account_info, payer_info, system_program_info, new_len)
+    } else {
+        account_info.realloc(new_len, false)
+    }
+}
+
+/// Closes the program account, handing its lamports to the destination
+fn close_account(account_info: &AccountInfo, destination_info: &AccountInfo) -> ProgramResult {
+    let destination_lamports = destination_info
+        .lamports()
+        .checked_add(account_info.lamports())
+        .ok_or(ProgramError::InvalidArgument)?;
+    **destination_info.try_borrow_mut_lamports()? = destination_lamports;
+    **account_info.try_borrow_mut_lamports()? = 0;
+    account_info.realloc(0, false)
+}
+
+/// Tells how many lamports and tokens the instruction transfers out of the
+/// wallet it names first, charged to the budget of a session key signing it.
+/// Subscriptions are left to the owner, their collections being charged to
+/// no budget.
+fn session_spend(instruction: &ProgramInstruction) -> (u64, u64) {
+    match instruction {
+        ProgramInstruction::WalletTransferLamports(amount)
+        | ProgramInstruction::QueueWithdrawal(ProposalAction::TransferLamports { amount, .. }) => {
+            (*amount, 0)
+        }
+        ProgramInstruction::WalletTransferSpl(amount, _, _)
+        | ProgramInstruction::TransferFrom(amount, _)
+        | ProgramInstruction::QueueWithdrawal(ProposalAction::TransferSpl { amount, .. }) => {
+            (0, *amount)
+        }
+        _ => (0, 0),
+    }
+}
+
+/// Checks that the session key has not expired yet
+fn check_session_key_expiry(session_key: &SessionKey) -> ProgramResult {
+    let slot = Clock::get()?.slot;
+    if slot > session_key.expiry_slot {
+        msg!(
+            "Fail: The session key {} expired at slot {}.",
+            session_key.key,
+            session_key.expiry_slot
+        );
+        return Err(SampleError::SessionKeyExpired.into());
+    }
+    Ok(())
+}
+
+/// Checks that the session key is live and allowed the instruction
+fn check_session_key_scope(
+    session_key: &SessionKey,
+    instruction: &ProgramInstruction,
+) -> ProgramResult {
+    check_session_key_expiry(session_key)?;
+    if !session_key.allowed_instructions.contains(&instruction.tag()?) {
+        msg!("Fail: The session key {} is not allowed {:?}.", session_key.key, instruction);
+        return Err(SampleError::SessionKeyNotAllowed.into());
+    }
+    Ok(())
+}
+
+/// Checks a session key signing, second in accounts, for the owner of the
+/// wallet first in accounts: the key has to be live and allowed the
+/// instruction, whose transfers are charged to its budget. Signers that are
+/// not session keys of a wallet are left to the handler.
+fn check_session_key(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction: &ProgramInstruction,
+) -> ProgramResult {
+    let (wallet_info, signer_info) = match accounts {
+        [wallet_info, signer_info, ..]
+            if wallet_info.owner == program_id && signer_info.is_signer =>
+        {
+            (wallet_info, signer_info)
+        }
+        _ => return Ok(()),
+    };
+    let mut wallet_state =
+        match ProgramAccountState::unpack_account(&wallet_info.try_borrow_data()?) {
+            Ok(wallet_state) if wallet_state.account_type() == AccountType::Wallet => wallet_state,
+            _ => return Ok(()),
+        };
+    let session_key = match wallet_state
+        .wallet_settings_mut()
+        .session_key_mut(signer_info.key)
+    {
+        Some(session_key) => session_key,
+        None => return Ok(()),
+    };
+    check_session_key_scope(session_key, instruction)?;
+    let (lamports, tokens) = session_spend(instruction);
+    if lamports == 0 && tokens == 0 {
+        return Ok(());
+    }
+    if let Err(error) = session_key.charge(lamports, tokens) {
+        msg!(
+            "Fail: The session key {} has {} lamports and {} tokens left.",
+            signer_info.key,
+            session_key.lamport_budget,
+            session_key.token_budget
+        );
+        return Err(error.into());
+    }
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+
+/// Checks that the wallet account is owned by our program and that its
+/// recorded owner, or one of its live session keys, signed the transaction,
+/// returning the wallet state. Session keys are only ever checked as the
+/// signer following the wallet, which `check_session_key` scoped already.
+/// Multisig wallets only act on the proposals their members approve.
+fn check_wallet_owner(
+    program_id: &Pubkey,
+    wallet_info: &AccountInfo,
+    owner_info: &AccountInfo,
+) -> Result<ProgramAccountState, ProgramError> {
+    if wallet_info.owner != program_id {
+        msg!(
+            "Fail: The wallet account owner is {} and it should be {}.",
+            wallet_info.owner,
+            program_id
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if !owner_info.is_signer {
+        msg!("Fail: The wallet owner {} did not sign.", owner_info.key);
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let wallet_state = ProgramAccountState::unpack_account(&wallet_info.try_borrow_data()?)?;
+    check_account_type(wallet_info, &wallet_state, AccountType::Wallet)?;
+    if wallet_state.owner() != owner_info.key {
+        let session_key = wallet_state
+            .wallet_settings()
+            .session_keys
+            .iter()
+            .find(|session_key| session_key.key == *owner_info.key);
+        match session_key {
+            Some(session_key) => check_session_key_expiry(session_key)?,
+            None => {
+                msg!(
+                    "Fail: The wallet is owned by {} and not by {}.",
+                    wallet_state.owner(),
+                    owner_info.key
+                );
+                return Err(ProgramError::IllegalOwner);
+            }
+        }
+    }
+    check_not_multisig(wallet_info, &wallet_state)?;
+    Ok(wallet_state)
+}
+
+/// Checks that the wallet is not a multisig wallet, whose transfers wait for
+/// the approval of its members
+fn check_not_multisig(
+    wallet_info: &AccountInfo,
+    wallet_state: &ProgramAccountState,
+) -> ProgramResult {
+    if wallet_state.wallet_settings().multisig.is_some() {
+        msg!("Fail: The wallet {} only transfers through proposals.", wallet_info.key);
+        return Err(SampleError::MultisigApprovalRequired.into());
+    }
+    Ok(())
+}
+
+/// Allocates the program derived wallet account of the owner, at the index,
+/// with just the space its state needs and stores the state
+fn create_wallet<'a>(
+    program_id: &Pubkey,
+    owner_info: &AccountInfo<'a>,
+    wallet_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    index: Option<u8>,
+    wallet_state: &ProgramAccountState,
+) -> ProgramResult {
+    if !owner_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let (expected_wallet, bump_seed) = find_wallet_address(program_id, owner_info.key, index);
+    if expected_wallet != *wallet_info.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if !wallet_info.data_is_empty() {
+        return Err(SampleError::AlreadyInitializedState.into());
+    }
+
+    // Sign for the wallet account with its own seeds
+    let space = wallet_state.packed_len()?;
+    let index_seed = index.map(|index| [index]);
+    let bump_seed = [bump_seed];
+    let mut signer_seeds: Vec<&[u8]> = vec![WALLET_SEED, owner_info.key.as_ref()];
+    if let Some(index_seed) = index_seed.as_ref() {
+        signer_seeds.push(index_seed);
+    }
+    signer_seeds.push(&bump_seed);
+    invoke_signed(
+        &system_instruction::create_account(
+            owner_info.key,
+            wallet_info.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[
+            owner_info.clone(),
+            wallet_info.clone(),
+            system_program_info.clone(),
+        ],
+        &[&signer_seeds],
+    )?;
+
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+
+/// Create the program derived wallet account of the owner, which is the
+/// first in accounts, followed by the wallet and the system program
+fn wallet_new(program_id: &Pubkey, accounts: &[AccountInfo], index: Option<u8>) -> ProgramResult {
+    msg!("Create wallet");
+    let account_info_iter = &mut accounts.iter();
+    let owner_info = next_account_info(account_info_iter)?;
+    let wallet_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    let mut wallet_state = ProgramAccountState::default();
+    wallet_state.set_account_type(AccountType::Wallet);
+    wallet_state.set_initialized();
+    wallet_state.set_owner(*owner_info.key);
+
+    create_wallet(
+        program_id,
+        owner_info,
+        wallet_info,
+        system_program_info,
+        index,
+        &wallet_state,
+    )
+}
+
+/// Checks that the multisig members are distinct, no more than a wallet
+/// holds, and that they can meet the threshold
+fn check_multisig(multisig: &Multisig) -> ProgramResult {
+    let mut members = multisig.members.clone();
+    members.sort();
+    members.dedup();
+    if members.len() != multisig.members.len()
+        || members.len() > MAX_MULTISIG_MEMBERS
+        || multisig.threshold == 0
+        || multisig.threshold as usize > members.len()
+    {
+        msg!(
+            "Fail: {} distinct of {} members can not have a threshold of {}.",
+            members.len(),
+            multisig.members.len(),
+            multisig.threshold
+        );
+        return Err(SampleError::InvalidMultisig.into());
+    }
+    Ok(())
+}
+
+/// Create a multisig wallet like `wallet_new`, whose transfers have to be
+/// proposed and approved by the threshold of its members
+fn wallet_new_multisig(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    index: Option<u8>,
+    members: Vec<Pubkey>,
+    threshold: u8,
+) -> ProgramResult {
+    msg!("Create multisig wallet");
+    let account_info_iter = &mut accounts.iter();
+    let owner_info = next_account_info(account_info_iter)?;
+    let wallet_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    let multisig = Multisig {
+        members,
+        threshold,
+        proposal_count: 0,
+    };
+    check_multisig(&multisig)?;
+
+    let mut wallet_state = ProgramAccountState::default();
+    wallet_state.set_account_type(AccountType::Wallet);
+    wallet_state.set_initialized();
+    wallet_state.set_owner(*owner_info.key);
+    wallet_state.wallet_settings_mut().multisig = Some(multisig);
+
+    create_wallet(
+        program_id,
+        owner_info,
+        wallet_info,
+        system_program_info,
+        index,
+        &wallet_state,
+    )
+}
+
+/// Transfers tokens between two token accounts of the mint, signing for the
+/// source authority with the given seeds, if any. Works with both SPL Token
+/// and Token-2022, the token program is determined by the owner of the mint.
+#[allow(clippy::too_many_arguments)]
+fn transfer_tokens_signed<'a>(
+    source_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    destination_info: &AccountInfo<'a>,
+    authority_info: &AccountInfo<'a>,
+    token_program_info: &AccountInfo<'a>,
+    memo_program_info: Option<&AccountInfo<'a>>,
+    amount: u64,
+    decimals: u8,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    // The mint tells which token program we are dealing with
+    check_spl_token_program_account(mint_info.owner)?;
+    if token_program_info.key != mint_info.owner {
+        msg!(
+            "Fail: The mint is owned by {} and not by {}.",
+            mint_info.owner,
+            token_program_info.key
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Both token accounts have to hold the mint being transferred
+    let source_data = source_info.try_borrow_data()?;
+    let source_account = StateWithExtensions::<Account>::unpack(&source_data)?;
+    if source_account.base.mint != *mint_info.key {
+        return Err(SampleError::SourceMintMismatch.into());
+    }
+    if source_account.base.amount < amount {
+        return Err(SampleError::InsufficientFundsForTransaction.into());
+    }
+    let destination_data = destination_info.try_borrow_data()?;
+    let destination_account = StateWithExtensions::<Account>::unpack(&destination_data)?;
+    if destination_account.base.mint != *mint_info.key {
+        return Err(SampleError::DestinationMintMismatch.into());
+    }
+    let memo_required = destination_account
+        .get_extension::<MemoTransfer>()
+        .map(|extension| bool::from(extension.require_incoming_transfer_memos))
+        .unwrap_or(false);
+
+    // The program uses `transfer_checked`, so the decimals the client expects
+    // have to match the mint.
+    let mint_data = mint_info.try_borrow_data()?;
+    let mint = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+    if mint.base.decimals != decimals {
+        return Err(SampleError::MintDecimalsMismatch.into());
+    }
+    // Mints with a transfer fee need the fee stated up front
+    let fee = match mint.get_extension::<TransferFeeConfig>() {
+        Ok(fee_config) => Some(
+            fee_config
+                .calculate_epoch_fee(Clock::get()?.epoch, amount)
+                .ok_or(ProgramError::InvalidArgument)?,
+        ),
+        Err(_) => None,
+    };
+    drop(source_data);
+    drop(destination_data);
+    drop(mint_data);
+
+    // Destinations requiring memos only accept a transfer right after one
+    let mut account_infos = vec![
+        source_info.clone(),
+        mint_info.clone(),
+        destination_info.clone(),
+        authority_info.clone(),
+        token_program_info.clone(), // not required, but better for clarity
+    ];
+    if memo_required {
+        let memo_program_info = memo_program_info.ok_or(SampleError::MemoProgramMissing)?;
+        if *memo_program_info.key != spl_memo::id() {
+            return Err(SampleError::MemoProgramMissing.into());
+        }
+        let memo = format!("Wallet transfer of {} tokens", amount);
+        invoke(
+            &spl_memo::build_memo(memo.as_bytes(), &[]),
+            &[memo_program_info.clone()],
+        )?;
+        account_infos.push(memo_program_info.clone());
+    }
+
+    // Invoke the transfer
+    let instruction = match fee {
+        Some(fee) => {
+            msg!("Attempting to transfer {} tokens with a fee of {}", amount, fee);
+            transfer_checked_with_fee(
+                token_program_info.key,
+                source_info.key,
+                mint_info.key,
+                destination_info.key,
+                authority_info.key,
+                &[], // no multisig allowed
+                amount,
+                decimals,
+                fee,
+            )?
+        }
+        None => {
+            msg!("Attempting to transfer {} tokens", amount);
+            transfer_checked(
+                token_program_info.key,
+                source_info.key,
+                mint_info.key,
+                destination_info.key,
+                authority_info.key,
+                &[], // no multisig allowed
+                amount,
+                decimals,
+            )?
+        }
+    };
+    invoke_signed(&instruction, &account_infos, signers_seeds)
+}
+
+/// Creates the recipients associated token account when it is missing. The
+/// wallet covers the rent of the new account and the wallet authority signs
+/// as the funder, which has nothing left to pay once the rent is in place.
+#[allow(clippy::too_many_arguments)]
+fn create_destination_if_missing<'a>(
+    wallet_info: &AccountInfo<'a>,
+    recipient_info: &AccountInfo<'a>,
+    destination_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    authority_info: &AccountInfo<'a>,
+    token_program_info: &AccountInfo<'a>,
+    associated_token_program_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    signer_seeds: &[&[u8]],
+) -> ProgramResult {
+    if *associated_token_program_info.key != spl_associated_token_account::id()
+        || *system_program_info.key != system_program::id()
+    {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let expected_destination = get_associated_token_address_with_program_id(
+        recipient_info.key,
+        mint_info.key,
+        token_program_info.key,
+    );
+    if expected_destination != *destination_info.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    if destination_info.data_is_empty() {
+        // Size the account like the associated token program will
+        let mint_data = mint_info.try_borrow_data()?;
+        let mint = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+        let mut extension_types =
+            ExtensionType::get_required_init_account_extensions(&mint.get_extension_types()?);
+        if *mint_info.owner == spl_token_2022::id() {
+            extension_types.push(ExtensionType::ImmutableOwner);
+        }
+        drop(mint_data);
+        let space = ExtensionType::get_account_len::<Account>(&extension_types);
+        let rent = Rent::get()?
+            .minimum_balance(space)
+            .saturating_sub(destination_info.lamports());
+        msg!("Creating associated token account {}", destination_info.key);
+        debit_wallet_lamports(wallet_info, destination_info, rent)?;
+    }
+
+    invoke_signed(
+        &create_associated_token_account_idempotent(
+            authority_info.key,
+            recipient_info.key,
+            mint_info.key,
+            token_program_info.key,
+        ),
+        &[
+            authority_info.clone(),
+            destination_info.clone(),
+            recipient_info.clone(),
+            mint_info.clone(),
+            system_program_info.clone(),
+            token_program_info.clone(),
+            associated_token_program_info.clone(),
+        ],
+        &[signer_seeds],
+    )
+}
+
+/// Counts the amount sent against the spending limit of the wallet for the
+/// asset, lamports without a mint, if it has one
+fn track_spending(wallet_info: &AccountInfo, mint: Option<&Pubkey>, amount: u64) -> ProgramResult {
+    let mut wallet_state = ProgramAccountState::unpack_account(&wallet_info.try_borrow_data()?)?;
+    let limit = match wallet_state.wallet_settings_mut().spending_limit_mut(mint) {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    if let Err(error) = limit.spend(Clock::get()?.unix_timestamp, amount) {
+        msg!(
+            "Fail: {} of {} spent in the rolling period, {} more exceeds the limit.",
+            limit.spent,
+            limit.amount,
+            amount
+        );
+        return Err(error.into());
+    }
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+
+/// Checks that transfers out of the wallet need not be queued
+fn check_not_time_locked(
+    wallet_info: &AccountInfo,
+    wallet_state: &ProgramAccountState,
+) -> ProgramResult {
+    let delay = wallet_state.wallet_settings().withdrawal_delay;
+    if delay > 0 {
+        msg!("Fail: Transfers out of {} wait {} seconds in the queue.", wallet_info.key, delay);
+        return Err(SampleError::WithdrawalDelayRequired.into());
+    }
+    Ok(())
+}
+
+/// Sends tokens out of a token account held by the wallet authority. The
+/// source, mint, destination, authority, token program, config and treasury
+/// token account are next in accounts, followed by the recipient, associated
+/// token program and system program when creating the recipients associated
+/// token account first, and by the optional memo program. The wallet
+/// transfer fee goes to the token account of the treasury.
+fn send_wallet_tokens<'a>(
+    program_id: &Pubkey,
+    wallet_info: &AccountInfo<'a>,
+    account_info_iter: &mut Iter<'_, AccountInfo<'a>>,
+    amount: u64,
+    decimals: u8,
+    create_destination: bool,
+) -> ProgramResult {
+    let source_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+    let destination_info = next_account_info(account_info_iter)?;
+    let authority_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let treasury_info = next_account_info(account_info_iter)?;
+
+    // The treasury takes its fee out of the amount sent
+    let config = load_config(program_id, config_info)?;
+    check_treasury_token_account(&config, treasury_info)?;
+    let fee = wallet_transfer_fee(&config.fees, amount)?;
+    track_spending(wallet_info, Some(mint_info.key), amount)?;
+
+    // In order to transfer from the source account, owned by the wallets
+    // program-derived authority, we must have the correct address and seeds.
+    let (expected_authority, bump_seed) =
+        find_wallet_authority_address(program_id, wallet_info.key);
+    if expected_authority != *authority_info.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    let bump_seed = [bump_seed];
+    let signer_seeds: &[&[u8]] = &[AUTHORITY_SEED, wallet_info.key.as_ref(), &bump_seed];
+
+    if create_destination {
+        let recipient_info = next_account_info(account_info_iter)?;
+        let associated_token_program_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        create_destination_if_missing(
+            wallet_info,
+            recipient_info,
+            destination_info,
+            mint_info,
+            authority_info,
+            token_program_info,
+            associated_token_program_info,
+            system_program_info,
+            signer_seeds,
+        )?;
+    }
+    let memo_program_info = next_account_info(account_info_iter).ok(); // optional
+
+    transfer_tokens_signed(
+        source_info,
+        mint_info,
+        destination_info,
+        authority_info,
+        token_program_info,
+        memo_program_info,
+        amount - fee,
+        decimals,
+        &[signer_seeds],
+    )?;
+    if fee > 0 {
+        transfer_tokens_signed(
+            source_info,
+            mint_info,
+            treasury_info,
+            authority_info,
+            token_program_info,
+            None,
+            fee,
+            decimals,
+            &[signer_seeds],
+        )?;
+    }
+    Ok(())
+}
+
+/// Transfer tokens out of a token account held by the wallet authority,
+/// optionally creating the recipients associated token account first. The
+/// wallet transfer fee goes to the token account of the treasury.
+pub fn wallet_transfer_spl(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    decimals: u8,
+    create_destination: bool,
+) -> ProgramResult {
+    // Create an iterator to safely reference accounts in the slice
+    let account_info_iter = &mut accounts.iter();
+
+    // As part of the program specification the instruction gives the wallet
+    // and its owner, followed by the accounts `send_wallet_tokens` takes
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+
+    let wallet_state = check_wallet_owner(program_id, wallet_info, owner_info)?;
+    check_not_time_locked(wallet_info, &wallet_state)?;
+    send_wallet_tokens(
+        program_id,
+        wallet_info,
+        account_info_iter,
+        amount,
+        decimals,
+        create_destination,
+    )
+}
+
+/// Sends lamports out of the wallet, keeping it rent exempt, to the
+/// destination next in accounts. The config and the treasury follow, the
+/// wallet transfer fee goes to the treasury.
+fn send_wallet_lamports(
+    program_id: &Pubkey,
+    wallet_info: &AccountInfo,
+    account_info_iter: &mut Iter<AccountInfo>,
+    amount: u64,
+) -> ProgramResult {
+    let destination_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let treasury_info = next_account_info(account_info_iter)?;
+
+    let config = load_config(program_id, config_info)?;
+    check_treasury(&config, treasury_info)?;
+    let fee = wallet_transfer_fee(&config.fees, amount)?;
+    track_spending(wallet_info, None, amount)?;
+
+    msg!("Attempting to transfer {} lamports", amount - fee);
+    debit_wallet_lamports(wallet_info, destination_info, amount - fee)?;
+    debit_wallet_lamports(wallet_info, treasury_info, fee)
+}
+
+/// Transfer lamports out of the wallet, which is the first in accounts,
+/// keeping the wallet rent exempt. The wallet transfer fee goes to the
+/// treasury following the config.
+pub fn wallet_transfer_lamports(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let source_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+
+    let wallet_state = check_wallet_owner(program_id, source_info, owner_info)?;
+    if amount > wallet_state.wallet_settings().withdrawal_threshold {
+        check_not_time_locked(source_info, &wallet_state)?;
+    }
+    send_wallet_lamports(program_id, source_info, account_info_iter, amount)
+}
+
+/// Checks that the wallet is a multisig wallet and that the member is one of
+/// its members, returning the wallet state and its multisig
+fn check_multisig_member(
+    wallet_info: &AccountInfo,
+    member_info: &AccountInfo,
+) -> Result<(ProgramAccountState, Multisig), ProgramError> {
+    let wallet_state = ProgramAccountState::unpack_account(&wallet_info.try_borrow_data()?)?;
+    check_account_type(wallet_info, &wallet_state, AccountType::Wallet)?;
+    let multisig = match wallet_state.wallet_settings().multisig.clone() {
+        Some(multisig) => multisig,
+        None => {
+            msg!("Fail: The wallet {} is not a multisig wallet.", wallet_info.key);
+            return Err(SampleError::InvalidMultisig.into());
+        }
+    };
+    if !multisig.is_member(member_info.key) {
+        msg!("Fail: {} is not a member of the wallet.", member_info.key);
+        return Err(SampleError::NotMultisigMember.into());
+    }
+    Ok((wallet_state, multisig))
+}
+
+/// Reads the proposal, which has to be one of the wallet still pending
+fn load_pending_proposal(
+    wallet_info: &AccountInfo,
+    proposal_info: &AccountInfo,
+) -> Result<Proposal, ProgramError> {
+    let proposal = Proposal::unpack_account(&proposal_info.try_borrow_data()?)?;
+    if proposal.wallet != *wallet_info.key {
+        msg!("Fail: The proposal is for the wallet {}.", proposal.wallet);
+        return Err(SampleError::ProposalAccountMismatch.into());
+    }
+    if proposal.status != ProposalStatus::Pending {
+        msg!("Fail: The proposal is {:?}.", proposal.status);
+        return Err(SampleError::ProposalNotPending.into());
+    }
+    Ok(proposal)
+}
+
+/// Record an action of the multisig wallet, the first in accounts, as
+/// proposed and approved by the member following it, who pays for the
+/// proposal account next in accounts ahead of the system program
+fn propose_action(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    action: ProposalAction,
+) -> ProgramResult {
+    msg!("Propose action");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let member_info = next_account_info(account_info_iter)?;
+    let proposal_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    let (mut wallet_state, mut multisig) = check_multisig_member(wallet_info, member_info)?;
+    let index = multisig.proposal_count;
+    let (expected_proposal, bump_seed) = find_proposal_address(program_id, wallet_info.key, index);
+    if expected_proposal != *proposal_info.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let proposal = Proposal {
+        wallet: *wallet_info.key,
+        action,
+        approvals: vec![*member_info.key],
+        rejections: Vec::new(),
+        status: ProposalStatus::Pending,
+    };
+    // Leave room for the votes of the other members
+    let space = proposal.packed_len(multisig.members.len() - 1)?;
+    let index_seed = index.to_le_bytes();
+    invoke_signed(
+        &system_instruction::create_account(
+            member_info.key,
+            proposal_info.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[
+            member_info.clone(),
+            proposal_info.clone(),
+            system_program_info.clone(),
+        ],
+        &[&[PROPOSAL_SEED, wallet_info.key.as_ref(), &index_seed, &[bump_seed]]],
+    )?;
+    proposal.pack_account(&mut proposal_info.try_borrow_mut_data()?)?;
+
+    msg!("Proposal {} of wallet {}", index, wallet_info.key);
+    multisig.proposal_count += 1;
+    wallet_state.wallet_settings_mut().multisig = Some(multisig);
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+
+/// Record the vote of the member, second in accounts after the multisig
+/// wallet, on the proposal following it. Once too many members reject the
+/// proposal to ever meet the threshold it is closed as rejected.
+fn vote_on_proposal(accounts: &[AccountInfo], approve: bool) -> ProgramResult {
+    msg!("Vote on proposal");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let member_info = next_account_info(account_info_iter)?;
+    let proposal_info = next_account_info(account_info_iter)?;
+
+    let (_, multisig) = check_multisig_member(wallet_info, member_info)?;
+    let mut proposal = load_pending_proposal(wallet_info, proposal_info)?;
+    if proposal.has_voted(member_info.key) {
+        return Err(SampleError::ProposalAlreadyVoted.into());
+    }
+    if approve {
+        proposal.approvals.push(*member_info.key);
+    } else {
+        proposal.rejections.push(*member_info.key);
+        if proposal.rejections.len() > multisig.members.len() - multisig.threshold as usize {
+            msg!("Proposal rejected");
+            proposal.status = ProposalStatus::Rejected;
+        }
+    }
+    proposal.pack_account(&mut proposal_info.try_borrow_mut_data()?)
+}
+
+/// Checks that the next accounts are the ones the proposal names
+fn check_proposal_accounts(
+    mut account_info_iter: Iter<AccountInfo>,
+    named: &[Pubkey],
+) -> ProgramResult {
+    for named in named {
+        let account_info = next_account_info(&mut account_info_iter)?;
+        if account_info.key != named {
+            msg!("Fail: The proposal names {} and not {}.", named, account_info.key);
+            return Err(SampleError::ProposalAccountMismatch.into());
+        }
+    }
+    Ok(())
+}
+
+/// Invokes the proposed instruction with the wallet authority signing. The
+/// invoked program and the accounts of the instruction are next in accounts.
+fn invoke_proposed_instruction<'a>(
+    program_id: &Pubkey,
+    wallet_info: &AccountInfo<'a>,
+    account_info_iter: &mut Iter<'_, AccountInfo<'a>>,
+    proposed: ProposedInstruction,
+) -> ProgramResult {
+    let mut named = vec![proposed.program_id];
+    named.extend(proposed.accounts.iter().map(|meta| meta.pubkey));
+    check_proposal_accounts(account_info_iter.clone(), &named)?;
+    let account_infos: Vec<AccountInfo> = account_info_iter.take(named.len()).cloned().collect();
+
+    let (authority, bump_seed) = find_wallet_authority_address(program_id, wallet_info.key);
+    msg!("Invoking {} signed by {}", proposed.program_id, authority);
+    invoke_signed(
+        &proposed.into(),
+        &account_infos,
+        &[&[AUTHORITY_SEED, wallet_info.key.as_ref(), &[bump_seed]]],
+    )
+}
+
+/// Execute the proposal, third in accounts after the multisig wallet and a
+/// member, once the threshold of members approved it. The accounts of its
+/// action follow as `execute_action` takes them.
+fn execute_proposal(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Execute proposal");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let member_info = next_account_info(account_info_iter)?;
+    let proposal_info = next_account_info(account_info_iter)?;
+
+    let (_, multisig) = check_multisig_member(wallet_info, member_info)?;
+    let mut proposal = load_pending_proposal(wallet_info, proposal_info)?;
+    if proposal.approvals.len() < multisig.threshold as usize {
+        msg!(
+            "Fail: The proposal has {} of {} approvals.",
+            proposal.approvals.len(),
+            multisig.threshold
+        );
+        return Err(SampleError::ProposalThresholdNotMet.into());
+    }
+    proposal.status = ProposalStatus::Executed;
+    proposal.pack_account(&mut proposal_info.try_borrow_mut_data()?)?;
+
+    execute_action(program_id, wallet_info, account_info_iter, proposal.action)
+}
+
+/// Takes the action out of the wallet. The accounts of a transfer follow as
+/// `send_wallet_lamports` or `send_wallet_tokens` take them, those of an
+/// instruction as `invoke_proposed_instruction` takes them, and have to be
+/// the ones the action names.
+fn execute_action<'a>(
+    program_id: &Pubkey,
+    wallet_info: &AccountInfo<'a>,
+    account_info_iter: &mut Iter<'_, AccountInfo<'a>>,
+    action: ProposalAction,
+) -> ProgramResult {
+    match action {
+        ProposalAction::TransferLamports {
+            destination,
+            amount,
+        } => {
+            check_proposal_accounts(account_info_iter.clone(), &[destination])?;
+            send_wallet_lamports(program_id, wallet_info, account_info_iter, amount)
+        }
+        ProposalAction::TransferSpl {
+            source,
+            mint,
+            destination,
+            amount,
+            decimals,
+        } => {
+            check_proposal_accounts(account_info_iter.clone(), &[source, mint, destination])?;
+            send_wallet_tokens(
+                program_id,
+                wallet_info,
+                account_info_iter,
+                amount,
+                decimals,
+                false,
+            )
+        }
+        ProposalAction::Invoke(proposed) => {
+            invoke_proposed_instruction(program_id, wallet_info, account_info_iter, proposed)
+        }
+    }
+}
+
+/// Initialize the programs account, which is the first in accounts. The
+/// account is allocated with `ACCOUNT_STATE_SPACE`, which always holds the
+/// first key/pair, so unlike minting it never grows and takes no payer.
+fn initialize_account(accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Initialize account");
+    let account_info_iter = &mut accounts.iter();
+    let program_account = next_account_info(account_info_iter)?;
+    let mut account_data = program_account.data.borrow_mut();
+    // Just using unpack will check to see if initialized and will
+    // fail if not
+    let mut account_state = ProgramAccountState::unpack_from_slice(&account_data)?;
+    check_account_type(program_account, &account_state, AccountType::KeyValueStore)?;
+    // Where this is a logic error in trying to initialize the same
+    // account more than once
+    if account_state.is_initialized() {
+        return Err(SampleError::AlreadyInitializedState.into());
+    } else {
+        account_state.set_initialized();
+    }
+
+    let key = "a1".to_string();
+    let value = "b1".to_string();
+    account_state.add(key, value)?;
+    account_state.pack_account(&mut account_data)
+}
+
+/// Adds a key/pair to the program account, growing the account with rent
+/// from the payer when the key/pair no longer fits
+fn add_keypair_to_account<'a>(
+    program_account: &AccountInfo<'a>,
+    payer_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    key: String,
+    value: String,
+) -> ProgramResult {
+    // Unpacking an uninitialized account state will fail
+    let mut account_state =
+        ProgramAccountState::unpack_account(&program_account.try_borrow_data()?)?;
+    check_account_type(program_account, &account_state, AccountType::KeyValueStore)?;
+    account_state.add(key, value)?;
+    let packed_len = account_state.packed_len()?;
+    if packed_len > program_account.data_len() {
+        grow_account(program_account, payer_info, system_program_info, packed_len)?;
+    }
+    account_state.pack_account(&mut program_account.try_borrow_mut_data()?)
+}
+/// Mint a key/pair to the programs account, which is the first in accounts
+/// followed by the payer and the system program
+fn mint_keypair_to_account(accounts: &[AccountInfo], key: String, value: String) -> ProgramResult {
+    msg!("Mint to account");
+    let account_info_iter = &mut accounts.iter();
+    let program_account = next_account_info(account_info_iter)?;
+    let payer_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    add_keypair_to_account(program_account, payer_info, system_program_info, key, value)
+}
+/// Mint a key/value pair extracting a service fee for the effort
+fn mint_keypair_to_account_with_fee(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    key: String,
+    value: String,
+) -> ProgramResult {
+    msg!("Mint to account");
+    // Charge for service
+    let account_info_iter = &mut accounts.iter();
+    let program_account = next_account_info(account_info_iter)?;
+    let service_account = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let fee_tiers_info = next_account_info(account_info_iter)?;
+    let payer_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let config = load_config(program_id, config_info)?;
+    let fee_tiers = load_fee_tiers(program_id, fee_tiers_info)?;
+    let fee_accounts = FeeAccounts::next(program_id, &config, service_account, account_info_iter)?;
+    // The payer of the mint is as much the payer of the fee
+    fee_accounts.check_not_self_referred(payer_info.key)?;
+    charge_service_fee(
+        &config,
+        &fee_tiers,
+        SampleServiceFees::Minting,
+        program_account,
+        &fee_accounts,
+    )?;
+    // Invoke the actual mint
+    add_keypair_to_account(program_account, payer_info, system_program_info, key, value)
+}
+/// Moves a key/pair from one program account to another, growing the "to"
+/// account at the expense of the payer and shrinking the "from" account
+fn move_keypair_between_accounts<'a>(
+    from_program_account: &AccountInfo<'a>,
+    to_program_account: &AccountInfo<'a>,
+    payer_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    key: String,
+) -> ProgramResult {
+    // Transfer from this account
+    let mut from_account_state =
+        ProgramAccountState::unpack_account(&from_program_account.try_borrow_data()?)?;
+    check_account_type(from_program_account, &from_account_state, AccountType::KeyValueStore)?;
+    // To this account
+    let mut to_account_state =
+        ProgramAccountState::unpack_account(&to_program_account.try_borrow_data()?)?;
+    check_account_type(to_program_account, &to_account_state, AccountType::KeyValueStore)?;
+    // Transfer the goods
+    let value = from_account_state.remove(&key)?;
+    to_account_state.add(key, value)?;
+    let packed_len = to_account_state.packed_len()?;
+    if packed_len > to_program_account.data_len() {
+        grow_account(to_program_account, payer_info, system_program_info, packed_len)?;
+    }
+    to_account_state.pack_account(&mut to_program_account.try_borrow_mut_data()?)?;
+    from_program_account.realloc(from_account_state.packed_len()?, false)?;
+    from_account_state.pack_account(&mut from_program_account.try_borrow_mut_data()?)
+}
+/// Transfer a key/pair from one program account to another
+/// "from" account is first and "to" account is second  in accounts,
+/// followed by the payer and the system program to grow the "to" account
+fn transfer_keypair_to_account(accounts: &[AccountInfo], key: String) -> ProgramResult {
+    msg!("Transfer from account");
+    let account_info_iter = &mut accounts.iter();
+    let from_program_account = next_account_info(account_info_iter)?;
+    let to_program_account = next_account_info(account_info_iter)?;
+    let payer_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    move_keypair_between_accounts(
+        from_program_account,
+        to_program_account,
+        payer_info,
+        system_program_info,
+        key,
+    )
+}
+/// Transfer key/value pair extracting a service fee for the effort
+fn transfer_keypair_to_account_with_fee(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    key: String,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let from_account = next_account_info(account_info_iter)?;
+    let to_account = next_account_info(account_info_iter)?;
+    let service_account = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let fee_tiers_info = next_account_info(account_info_iter)?;
+    let payer_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let config = load_config(program_id, config_info)?;
+    let fee_tiers = load_fee_tiers(program_id, fee_tiers_info)?;
+    let fee_accounts = FeeAccounts::next(program_id, &config, service_account, account_info_iter)?;
+    // The payer of the transfer is as much the payer of the fee
+    fee_accounts.check_not_self_referred(payer_info.key)?;
+
+    // Cost to "from account"
+    charge_service_fee(
+        &config,
+        &fee_tiers,
+        SampleServiceFees::Transfering,
+        from_account,
+        &fee_accounts,
+    )?;
+    // Cost to "to account"
+    charge_service_fee(
+        &config,
+        &fee_tiers,
+        SampleServiceFees::Minting,
+        to_account,
+        &fee_accounts,
+    )?;
+    // Invoke the actual transfer
+    move_keypair_between_accounts(from_account, to_account, payer_info, system_program_info, key)
+}
+/// Burn a key/pair from the programs account, which is the first in accounts,
+/// shrinking the account to what is left
+fn burn_keypair_from_account(accounts: &[AccountInfo], key: String) -> ProgramResult {
+    msg!("Burn from account");
+    let account_info_iter = &mut accounts.iter();
+    let program_account = next_account_info(account_info_iter)?;
+    let mut account_state =
+        ProgramAccountState::unpack_from_slice(&program_account.try_borrow_data()?)?;
+    check_account_type(program_account, &account_state, AccountType::KeyValueStore)?;
+    match account_state.remove(&key) {
+        Ok(_) => {
+            program_account.realloc(account_state.packed_len()?, false)?;
+            account_state.pack_account(&mut program_account.try_borrow_mut_data()?)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+/// Burn a key/pair extracting a service fee for the effort
+fn burn_keypair_from_account_with_fee(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    key: String,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let program_account = next_account_info(account_info_iter)?;
+    let service_account = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let fee_tiers_info = next_account_info(account_info_iter)?;
+    let config = load_config(program_id, config_info)?;
+    let fee_tiers = load_fee_tiers(program_id, fee_tiers_info)?;
+    let fee_accounts = FeeAccounts::next(program_id, &config, service_account, account_info_iter)?;
+    // Charge for service
+    charge_service_fee(
+        &config,
+        &fee_tiers,
+        SampleServiceFees::Burning,
+        program_account,
+        &fee_accounts,
+    )?;
+    // Invoke the actual burn
+    burn_keypair_from_account(accounts, key)?;
+    Ok(())
+}
+/// Rewrite the programs account, which is the first in accounts, from an
+/// older layout version into the current one. The payer and the system
+/// program follow to grow the account when the new layout needs the room.
+fn migrate_account(accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Migrate account");
+    let account_info_iter = &mut accounts.iter();
+    let program_account = next_account_info(account_info_iter)?;
+    let payer_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    let layout_version = ProgramAccountState::layout_version(&program_account.try_borrow_data()?)?;
+    if layout_version == LAYOUT_VERSION {
+        msg!("Account is already at layout version {}", layout_version);
+        return Ok(());
+    }
+    let account_state =
+        ProgramAccountState::unpack_from_slice(&program_account.try_borrow_data()?)?;
+    let packed_len = account_state.packed_len()?;
+    if packed_len > program_account.data_len() {
+        grow_account(program_account, payer_info, system_program_info, packed_len)?;
+    }
+    msg!(
+        "Migrating account from layout version {} to {}",
+        layout_version,
+        LAYOUT_VERSION
+    );
+    account_state.pack_account(&mut program_account.try_borrow_mut_data()?)
+}
+/// Checks that the admin is the upgrade authority of the program, the only
+/// party entitled to set the program config up
+fn check_upgrade_authority(
+    program_id: &Pubkey,
+    program_data_info: &AccountInfo,
+    admin_info: &AccountInfo,
+) -> ProgramResult {
+    let (expected_program_data, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if expected_program_data != *program_data_info.key
+        || *program_data_info.owner != bpf_loader_upgradeable::id()
+    {
+        msg!("Fail: {} is not the program data account.", program_data_info.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let upgrade_authority = match bincode::deserialize(&program_data_info.try_borrow_data()?) {
+        Ok(UpgradeableLoaderState::ProgramData {
+            upgrade_authority_address,
+            ..
+        }) => upgrade_authority_address,
+        _ => return Err(ProgramError::InvalidAccountData),
+    };
+    if upgrade_authority != Some(*admin_info.key) {
+        msg!("Fail: {} is not the program upgrade authority.", admin_info.key);
+        return Err(SampleError::InvalidConfigAdmin.into());
+    }
+    Ok(())
+}
+/// Create the program config account. The admin, which is the first in
+/// accounts and has to be the program upgrade authority, pays for it. The
+/// config, fee recipient, program data account and system program follow.
+fn initialize_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    fees: FeeSchedule,
+) -> ProgramResult {
+    msg!("Initialize config");
+    let account_info_iter = &mut accounts.iter();
+    let admin_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let fee_recipient_info = next_account_info(account_info_iter)?;
+    let program_data_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    let (expected_config, bump_seed) = find_config_address(program_id);
+    if expected_config != *config_info.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if !config_info.data_is_empty() {
+        return Err(SampleError::AlreadyInitializedState.into());
+    }
+    check_upgrade_authority(program_id, program_data_info, admin_info)?;
+    check_fee_schedule(&fees)?;
+
+    let config = ProgramConfig {
+        admin: *admin_info.key,
+        fee_recipient: *fee_recipient_info.key,
+        fees,
+    };
+    let space = config.packed_len()?;
+    invoke_signed(
+        &system_instruction::create_account(
+            admin_info.key,
+            config_info.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[
+            admin_info.clone(),
+            config_info.clone(),
+            system_program_info.clone(),
+        ],
+        &[&[CONFIG_SEED, &[bump_seed]]],
+    )?;
+    config.pack_account(&mut config_info.try_borrow_mut_data()?)
+}
+/// Replace the fee schedule of the program config, which is the second in
+/// accounts after its admin. The admin pays for the config to grow, through
+/// the system program following the config.
+fn update_fees(program_id: &Pubkey, accounts: &[AccountInfo], fees: FeeSchedule) -> ProgramResult {
+    msg!("Update fees");
+    let account_info_iter = &mut accounts.iter();
+    let admin_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    let mut config = load_config(program_id, config_info)?;
+    if config.admin != *admin_info.key {
+        msg!("Fail: The config admin is {} and not {}.", config.admin, admin_info.key);
+        return Err(SampleError::InvalidConfigAdmin.into());
+    }
+    check_fee_schedule(&fees)?;
+    msg!("Fees changed from {:?} to {:?}", config.fees, fees);
+    config.fees = fees;
+    resize_account(config_info, admin_info, system_program_info, config.packed_len()?)?;
+    config.pack_account(&mut config_info.try_borrow_mut_data()?)
+}
+/// Create the referral account of the referrer, which is the first in
+/// accounts and pays for it, followed by the referral account and the
+/// system program
+fn register_referrer(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Register referrer");
+    let account_info_iter = &mut accounts.iter();
+    let referrer_info = next_account_info(account_info_iter)?;
+    let referral_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    let (expected_referral, bump_seed) = find_referral_address(program_id, referrer_info.key);
+    if expected_referral != *referral_info.key {
+        return Err(ProgramError::InvalidSeeds);
     }
-    Ok(())
+    if !referral_info.data_is_empty() {
+        return Err(SampleError::AlreadyInitializedState.into());
+    }
+
+    let referral = ReferralAccount {
+        referrer: *referrer_info.key,
+    };
+    let space = referral.packed_len()?;
+    invoke_signed(
+        &system_instruction::create_account(
+            referrer_info.key,
+            referral_info.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[
+            referrer_info.clone(),
+            referral_info.clone(),
+            system_program_info.clone(),
+        ],
+        &[&[REFERRAL_SEED, referrer_info.key.as_ref(), &[bump_seed]]],
+    )?;
+    referral.pack_account(&mut referral_info.try_borrow_mut_data()?)
 }
+/// Withdraw the rewards of the referral account, the second in accounts after
+/// its referrer, to the destination. Lamports above the rent exempt minimum
+/// are always claimed, tokens when the referral token account, its mint, the
+/// destination token account and the token program follow.
+fn claim_referral_rewards(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Claim referral rewards");
+    let account_info_iter = &mut accounts.iter();
+    let referrer_info = next_account_info(account_info_iter)?;
+    let referral_info = next_account_info(account_info_iter)?;
+    let destination_info = next_account_info(account_info_iter)?;
 
-/// Extracts the service fee from the users program account and
-/// credits the service account
-fn charge_service_fee(
-    program_account: &AccountInfo,
-    service_account: &AccountInfo,
-    amount: u64,
-) -> ProgramResult {
-    // If tracking can not afford transaction fee
-    if **program_account.try_borrow_lamports()? < amount {
-        return Err(SampleError::InsufficientFundsForTransaction.into());
+    let referral = check_referral(program_id, referral_info)?;
+    if referral.referrer != *referrer_info.key {
+        msg!("Fail: The referrer is {} and not {}.", referral.referrer, referrer_info.key);
+        return Err(ProgramError::IllegalOwner);
     }
-    // Debit user and credit service
-    **program_account.try_borrow_mut_lamports()? -= amount;
-    **service_account.try_borrow_mut_lamports()? += amount;
-    Ok(())
-}
 
-fn wallet_new(accounts: &[AccountInfo]) -> ProgramResult {
-    msg!("wallet_new");
+    let rewards = referral_info
+        .lamports()
+        .saturating_sub(Rent::get()?.minimum_balance(referral_info.data_len()));
+    msg!("Claiming {} lamports of referral rewards", rewards);
+    debit_wallet_lamports(referral_info, destination_info, rewards)?;
 
-    let key = "a2".to_string();
-    let value = "b2".to_string();
-    
-    let account_info_iter = &mut accounts.iter();
-    let program_account = next_account_info(account_info_iter)?;
-    let mut account_data = program_account.data.borrow_mut();
+    if let Ok(referral_token_info) = next_account_info(account_info_iter) {
+        let mint_info = next_account_info(account_info_iter)?;
+        let destination_token_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
 
-    let mut account_state = ProgramAccountState::unpack(&account_data)?;
-    account_state.add(key, value)?;
-    ProgramAccountState::pack(account_state, &mut account_data)?;
+        let referral_token_data = referral_token_info.try_borrow_data()?;
+        let token_rewards = StateWithExtensions::<Account>::unpack(&referral_token_data)?
+            .base
+            .amount;
+        drop(referral_token_data);
+        let mint_data = mint_info.try_borrow_data()?;
+        let decimals = StateWithExtensions::<Mint>::unpack(&mint_data)?.base.decimals;
+        drop(mint_data);
 
+        let (_, bump_seed) = find_referral_address(program_id, referrer_info.key);
+        msg!("Claiming {} tokens of referral rewards", token_rewards);
+        transfer_tokens_signed(
+            referral_token_info,
+            mint_info,
+            destination_token_info,
+            referral_info,
+            token_program_info,
+            None,
+            token_rewards,
+            decimals,
+            &[&[REFERRAL_SEED, referrer_info.key.as_ref(), &[bump_seed]]],
+        )?;
+    }
     Ok(())
 }
-
-pub fn wallet_transfer_spl(
+/// Set the fee discount of a payer in percent, 0 dropping the discount and
+/// 100 exempting the payer. The config admin, first in accounts, pays for
+/// the fee tiers account following the config, ahead of the system program.
+fn set_fee_discount(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    _instruction_data: &[u8],
+    payer: Pubkey,
+    discount: u8,
 ) -> ProgramResult {
-    // Create an iterator to safely reference accounts in the slice
+    msg!("Set fee discount");
     let account_info_iter = &mut accounts.iter();
+    let admin_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let fee_tiers_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
 
-    // As part of the program specification the instruction gives:
-    let source_info = next_account_info(account_info_iter)?; // 1.
-    let mint_info = next_account_info(account_info_iter)?; // 2.
-    let destination_info = next_account_info(account_info_iter)?; // 3.
-    let authority_info = next_account_info(account_info_iter)?; // 4.
-    let token_program_info = next_account_info(account_info_iter)?; // 5.
-
-    // In order to transfer from the source account, owned by the program-derived
-    // address, we must have the correct address and seeds.
-    let (expected_authority, bump_seed) = Pubkey::find_program_address(&[b"authority"], program_id);
-    if expected_authority != *authority_info.key {
-        return Err(ProgramError::InvalidSeeds);
+    let config = load_config(program_id, config_info)?;
+    if config.admin != *admin_info.key {
+        msg!("Fail: The config admin is {} and not {}.", config.admin, admin_info.key);
+        return Err(SampleError::InvalidConfigAdmin.into());
     }
+    if discount > FEE_EXEMPT_DISCOUNT {
+        msg!("Fail: A discount of {}% is out of range.", discount);
+        return Err(SampleError::InvalidFeeSchedule.into());
+    }
+    let mut fee_tiers = load_fee_tiers(program_id, fee_tiers_info)?;
+    msg!(
+        "Fee discount of {} changed from {}% to {}%",
+        payer,
+        fee_tiers.discount(&payer),
+        discount
+    );
+    fee_tiers.set_discount(payer, discount);
 
-    // The program transfers everything out of its account, so extract that from
-    // the account data.
-    let source_account = Account::unpack(&source_info.try_borrow_data()?)?;
-    let amount = source_account.amount;
-
-    // The program uses `transfer_checked`, which requires the number of decimals
-    // in the mint, so extract that from the account data too.
-    let mint = Mint::unpack(&mint_info.try_borrow_data()?)?;
-    let decimals = mint.decimals;
+    let space = fee_tiers.packed_len()?;
+    if fee_tiers_info.data_is_empty() {
+        let (_, bump_seed) = find_fee_tiers_address(program_id);
+        invoke_signed(
+            &system_instruction::create_account(
+                admin_info.key,
+                fee_tiers_info.key,
+                Rent::get()?.minimum_balance(space),
+                space as u64,
+                program_id,
+            ),
+            &[
+                admin_info.clone(),
+                fee_tiers_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[&[FEE_TIERS_SEED, &[bump_seed]]],
+        )?;
+    } else {
+        resize_account(fee_tiers_info, admin_info, system_program_info, space)?;
+    }
+    fee_tiers.pack_account(&mut fee_tiers_info.try_borrow_mut_data()?)
+}
+/// Set the spending limit of the wallet, first in accounts, on lamports or
+/// the tokens of the mint over a rolling period of seconds. The owner
+/// follows and pays for the wallet to grow, ahead of the system program. A
+/// period of 0 lifts the limit. Multisig wallets take no spending limit,
+/// their members approving every transfer instead.
+fn set_spending_limit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    mint: Option<Pubkey>,
+    amount: u64,
+    period: i64,
+) -> ProgramResult {
+    msg!("Set spending limit");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
 
-    // Invoke the transfer
-    msg!("Attempting to transfer {} tokens", amount);
-    invoke_signed(
-        &transfer_checked(
-            token_program_info.key,
-            source_info.key,
-            mint_info.key,
-            destination_info.key,
-            authority_info.key,
-            &[], // no multisig allowed
+    let mut wallet_state = check_wallet_owner_key(program_id, wallet_info, owner_info)?;
+    if period < 0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let now = Clock::get()?.unix_timestamp;
+    let settings = wallet_state.wallet_settings_mut();
+    if period == 0 {
+        settings.spending_limits.retain(|limit| limit.mint != mint);
+    } else if let Some(limit) = settings.spending_limit_mut(mint.as_ref()) {
+        // What was spent still counts, regained at the new rate from now on
+        limit.regain(now);
+        limit.amount = amount;
+        limit.period = period;
+    } else {
+        settings.spending_limits.push(SpendingLimit {
+            mint,
             amount,
-            decimals,
-        )
-        .unwrap(),
-        &[
-            source_info.clone(),
-            mint_info.clone(),
-            destination_info.clone(),
-            authority_info.clone(),
-            token_program_info.clone(), // not required, but better for clarity
-        ],
-        &[&[b"authority", &[bump_seed]]],
-    )
+            period,
+            updated_at: now,
+            spent: 0,
+        });
+    }
+    resize_account(
+        wallet_info,
+        owner_info,
+        system_program_info,
+        wallet_state.packed_len()?,
+    )?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
 }
+/// Replace the guardians of the wallet, first in accounts, and the number of
+/// them recovering it, dropping any recovery in progress. The owner follows
+/// and pays for the wallet to grow, ahead of the system program.
+fn set_guardians(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    guardians: Vec<Pubkey>,
+    recovery_threshold: u8,
+) -> ProgramResult {
+    msg!("Set guardians");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
 
-pub fn wallet_transfer_lamports(
+    let mut wallet_state = check_wallet_owner_key(program_id, wallet_info, owner_info)?;
+    let mut distinct = guardians.clone();
+    distinct.sort();
+    distinct.dedup();
+    if distinct.len() != guardians.len()
+        || guardians.len() > MAX_GUARDIANS
+        || recovery_threshold as usize > guardians.len()
+    {
+        msg!(
+            "Fail: {} distinct of {} guardians can not have a threshold of {}.",
+            distinct.len(),
+            guardians.len(),
+            recovery_threshold
+        );
+        return Err(SampleError::InvalidGuardians.into());
+    }
+    let settings = wallet_state.wallet_settings_mut();
+    settings.guardians = guardians;
+    settings.recovery_threshold = recovery_threshold;
+    settings.recovery = None;
+    resize_account(
+        wallet_info,
+        owner_info,
+        system_program_info,
+        wallet_state.packed_len()?,
+    )?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+/// Set the seconds withdrawals out of the wallet, first in accounts, wait in
+/// the queue and the lamports transferred without waiting. The owner follows
+/// and pays for the wallet to grow, ahead of the system program. Lowering
+/// the delay or raising the threshold is announced first and takes effect
+/// when set again once the current delay passed, so that a stolen owner key
+/// can not lift the time lock at once. Tightening it drops the announcement.
+fn set_withdrawal_delay(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
+    delay: i64,
+    threshold: u64,
 ) -> ProgramResult {
+    msg!("Set withdrawal delay");
     let account_info_iter = &mut accounts.iter();
-    let source_info = next_account_info(account_info_iter)?;
-    let destination_info = next_account_info(account_info_iter)?;
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
 
-    msg!("-------------- wallet_transfer_lamports");
+    let mut wallet_state = check_wallet_owner_key(program_id, wallet_info, owner_info)?;
+    if !(0..=MAX_WITHDRAWAL_DELAY).contains(&delay) {
+        msg!("Fail: The withdrawal delay is at most {} seconds.", MAX_WITHDRAWAL_DELAY);
+        return Err(SampleError::InvalidWithdrawalDelay.into());
+    }
+    let now = Clock::get()?.unix_timestamp;
+    let settings = wallet_state.wallet_settings_mut();
+    let loosened = delay < settings.withdrawal_delay || threshold > settings.withdrawal_threshold;
+    if loosened && settings.withdrawal_delay > 0 {
+        let announced = settings.withdrawal_policy_change.as_ref().filter(|change| {
+            change.withdrawal_delay == delay && change.withdrawal_threshold == threshold
+        });
+        match announced {
+            Some(change) if now < change.effective_time => {
+                msg!("Fail: The change takes effect in {} seconds.", change.effective_time - now);
+                return Err(SampleError::WithdrawalLocked.into());
+            }
+            Some(_) => {}
+            None => {
+                let change = WithdrawalPolicyChange {
+                    withdrawal_delay: delay,
+                    withdrawal_threshold: threshold,
+                    effective_time: now.saturating_add(settings.withdrawal_delay),
+                };
+                msg!("Withdrawal delay change takes effect at {}", change.effective_time);
+                settings.withdrawal_policy_change = Some(change);
+                resize_account(
+                    wallet_info,
+                    owner_info,
+                    system_program_info,
+                    wallet_state.packed_len()?,
+                )?;
+                return wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?);
+            }
+        }
+    }
+    settings.withdrawal_delay = delay;
+    settings.withdrawal_threshold = threshold;
+    settings.withdrawal_policy_change = None;
+    resize_account(
+        wallet_info,
+        owner_info,
+        system_program_info,
+        wallet_state.packed_len()?,
+    )?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+/// Queue a transfer out of the wallet, first in accounts, until the
+/// withdrawal delay passed. The owner follows and pays for the pending
+/// withdrawal account next in accounts, ahead of the system program.
+fn queue_withdrawal(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    action: ProposalAction,
+) -> ProgramResult {
+    msg!("Queue withdrawal");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+    let withdrawal_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
 
-    msg!("source_info {:?} {:?}", source_info.key, source_info.lamports);
-    msg!("destination_info {:?} {:?}", destination_info.key, destination_info.lamports);
+    let mut wallet_state = check_wallet_owner(program_id, wallet_info, owner_info)?;
+    if let ProposalAction::Invoke(_) = action {
+        msg!("Fail: Only transfers are withdrawn.");
+        return Err(SampleError::InvalidInstruction.into());
+    }
+    let settings = wallet_state.wallet_settings_mut();
+    let index = settings.withdrawal_count;
+    let (expected_withdrawal, bump_seed) =
+        find_withdrawal_address(program_id, wallet_info.key, index);
+    if expected_withdrawal != *withdrawal_info.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
 
-    **source_info.try_borrow_mut_lamports()? -= 5;
-    **destination_info.try_borrow_mut_lamports()? += 5;
+    let withdrawal = PendingWithdrawal {
+        wallet: *wallet_info.key,
+        action,
+        unlock_time: Clock::get()?
+            .unix_timestamp
+            .saturating_add(settings.withdrawal_delay),
+        owner_epoch: settings.owner_epoch,
+    };
+    let space = withdrawal.packed_len()?;
+    let index_seed = index.to_le_bytes();
+    invoke_signed(
+        &system_instruction::create_account(
+            owner_info.key,
+            withdrawal_info.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[
+            owner_info.clone(),
+            withdrawal_info.clone(),
+            system_program_info.clone(),
+        ],
+        &[&[WITHDRAWAL_SEED, wallet_info.key.as_ref(), &index_seed, &[bump_seed]]],
+    )?;
+    withdrawal.pack_account(&mut withdrawal_info.try_borrow_mut_data()?)?;
+    msg!("Withdrawal {} unlocks at {}", index, withdrawal.unlock_time);
 
+    settings.withdrawal_count += 1;
+    resize_account(
+        wallet_info,
+        owner_info,
+        system_program_info,
+        wallet_state.packed_len()?,
+    )?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+/// Reads the pending withdrawal, which has to be one of the wallet
+fn load_withdrawal(
+    wallet_info: &AccountInfo,
+    withdrawal_info: &AccountInfo,
+) -> Result<PendingWithdrawal, ProgramError> {
+    let withdrawal = PendingWithdrawal::unpack_account(&withdrawal_info.try_borrow_data()?)?;
+    if withdrawal.wallet != *wallet_info.key {
+        msg!("Fail: The withdrawal is out of the wallet {}.", withdrawal.wallet);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(withdrawal)
+}
+/// Checks that a withdrawal or subscription of the given owner epoch was set
+/// up by the current owner, rather than one the wallet was recovered from
+fn check_owner_epoch(
+    wallet_info: &AccountInfo,
+    wallet_state: &ProgramAccountState,
+    owner_epoch: u64,
+) -> ProgramResult {
+    if owner_epoch != wallet_state.wallet_settings().owner_epoch {
+        msg!("Fail: Set up before the wallet {} was recovered.", wallet_info.key);
+        return Err(SampleError::OwnerChanged.into());
+    }
     Ok(())
 }
+/// Execute the unlocked withdrawal, third in accounts after the wallet and
+/// its owner or one of its session keys, returning the rent of the closed
+/// withdrawal account to the owner following it. The accounts of the
+/// transfer follow as `execute_action` takes them.
+fn execute_withdrawal(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Execute withdrawal");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let signer_info = next_account_info(account_info_iter)?;
+    let withdrawal_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
 
-/// Initialize the programs account, which is the first in accounts
-fn initialize_account(accounts: &[AccountInfo]) -> ProgramResult {
-    msg!("Initialize account");
+    let wallet_state = check_wallet_owner(program_id, wallet_info, signer_info)?;
+    if wallet_state.owner() != owner_info.key {
+        msg!("Fail: The wallet is owned by {}.", wallet_state.owner());
+        return Err(ProgramError::IllegalOwner);
+    }
+    let withdrawal = load_withdrawal(wallet_info, withdrawal_info)?;
+    check_owner_epoch(wallet_info, &wallet_state, withdrawal.owner_epoch)?;
+    let now = Clock::get()?.unix_timestamp;
+    if now < withdrawal.unlock_time {
+        msg!("Fail: The withdrawal unlocks in {} seconds.", withdrawal.unlock_time - now);
+        return Err(SampleError::WithdrawalLocked.into());
+    }
+    close_account(withdrawal_info, owner_info)?;
+    execute_action(program_id, wallet_info, account_info_iter, withdrawal.action)
+}
+/// Cancel the withdrawal, third in accounts after the wallet and either its
+/// owner or one of its guardians, returning the rent of the closed
+/// withdrawal account to the owner following it
+fn cancel_withdrawal(accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Cancel withdrawal");
     let account_info_iter = &mut accounts.iter();
-    let program_account = next_account_info(account_info_iter)?;
-    let mut account_data = program_account.data.borrow_mut();
-    // Just using unpack will check to see if initialized and will
-    // fail if not
-    let mut account_state = ProgramAccountState::unpack_unchecked(&account_data)?;
-    // Where this is a logic error in trying to initialize the same
-    // account more than once
-    if account_state.is_initialized() {
-        return Err(SampleError::AlreadyInitializedState.into());
-    } else {
-        account_state.set_initialized();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let signer_info = next_account_info(account_info_iter)?;
+    let withdrawal_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+
+    let wallet_state = ProgramAccountState::unpack_account(&wallet_info.try_borrow_data()?)?;
+    check_account_type(wallet_info, &wallet_state, AccountType::Wallet)?;
+    if wallet_state.owner() != owner_info.key {
+        msg!("Fail: The wallet is owned by {}.", wallet_state.owner());
+        return Err(ProgramError::IllegalOwner);
+    }
+    let settings = wallet_state.wallet_settings();
+    if signer_info.key != owner_info.key && !settings.guardians.contains(signer_info.key) {
+        let session_key = settings
+            .session_keys
+            .iter()
+            .find(|session_key| session_key.key == *signer_info.key);
+        match session_key {
+            Some(session_key) => {
+                check_session_key_scope(session_key, &ProgramInstruction::CancelWithdrawal)?
+            }
+            None => {
+                msg!("Fail: {} is not a guardian of the wallet.", signer_info.key);
+                return Err(SampleError::NotGuardian.into());
+            }
+        }
+    }
+    load_withdrawal(wallet_info, withdrawal_info)?;
+    close_account(withdrawal_info, owner_info)
+}
+/// Checks that the signer is a guardian of the wallet able to recover it,
+/// returning the wallet state
+fn check_guardian(
+    wallet_info: &AccountInfo,
+    guardian_info: &AccountInfo,
+) -> Result<ProgramAccountState, ProgramError> {
+    let wallet_state = ProgramAccountState::unpack_account(&wallet_info.try_borrow_data()?)?;
+    check_account_type(wallet_info, &wallet_state, AccountType::Wallet)?;
+    let settings = wallet_state.wallet_settings();
+    if !settings.guardians.contains(guardian_info.key) {
+        msg!("Fail: {} is not a guardian of the wallet.", guardian_info.key);
+        return Err(SampleError::NotGuardian.into());
+    }
+    if settings.recovery_threshold == 0 {
+        msg!("Fail: The wallet {} can not be recovered.", wallet_info.key);
+        return Err(SampleError::InvalidGuardians.into());
     }
+    Ok(wallet_state)
+}
+/// Start handing the wallet, first in accounts, over to the new owner with
+/// the approval of the guardian following it, who pays for the wallet to
+/// grow ahead of the system program
+fn initiate_recovery(accounts: &[AccountInfo], new_owner: Pubkey) -> ProgramResult {
+    msg!("Initiate recovery");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let guardian_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
 
-    let key = "a1".to_string();
-    let value = "b1".to_string();
-    account_state.add(key, value)?;
+    let mut wallet_state = check_guardian(wallet_info, guardian_info)?;
+    let settings = wallet_state.wallet_settings_mut();
+    if settings.recovery.is_some() {
+        return Err(SampleError::RecoveryInProgress.into());
+    }
+    let mut recovery = PendingRecovery {
+        new_owner,
+        ..PendingRecovery::default()
+    };
+    recovery.approve(
+        *guardian_info.key,
+        settings.recovery_threshold,
+        Clock::get()?.unix_timestamp,
+    );
+    settings.recovery = Some(recovery);
+    resize_account(
+        wallet_info,
+        guardian_info,
+        system_program_info,
+        wallet_state.packed_len()?,
+    )?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+/// Approve the recovery of the wallet, first in accounts, as the guardian
+/// following it, who pays for the wallet to grow ahead of the system program
+fn approve_recovery(accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Approve recovery");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let guardian_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
 
-    ProgramAccountState::pack(account_state, &mut account_data).unwrap();
-    Ok(())
+    let mut wallet_state = check_guardian(wallet_info, guardian_info)?;
+    let settings = wallet_state.wallet_settings_mut();
+    let threshold = settings.recovery_threshold;
+    let recovery = settings
+        .recovery
+        .as_mut()
+        .ok_or(SampleError::NoRecoveryPending)?;
+    if recovery.approvals.contains(guardian_info.key) {
+        return Err(SampleError::ProposalAlreadyVoted.into());
+    }
+    recovery.approve(*guardian_info.key, threshold, Clock::get()?.unix_timestamp);
+    resize_account(
+        wallet_info,
+        guardian_info,
+        system_program_info,
+        wallet_state.packed_len()?,
+    )?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
 }
+/// Hand the wallet, the only account, over to the new owner once the
+/// guardians approved the recovery and the owner did not veto it in time
+fn finalize_recovery(accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Finalize recovery");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
 
-/// Mint a key/pair to the programs account, which is the first in accounts
-fn mint_keypair_to_account(accounts: &[AccountInfo], key: String, value: String) -> ProgramResult {
-    msg!("Mint to account");
+    let mut wallet_state = ProgramAccountState::unpack_account(&wallet_info.try_borrow_data()?)?;
+    check_account_type(wallet_info, &wallet_state, AccountType::Wallet)?;
+    let recovery = match wallet_state.wallet_settings_mut().recovery.take() {
+        Some(recovery) => recovery,
+        None => return Err(SampleError::NoRecoveryPending.into()),
+    };
+    let now = Clock::get()?.unix_timestamp;
+    match recovery.unlock_time {
+        Some(unlock_time) if now >= unlock_time => (),
+        unlock_time => {
+            msg!("Fail: The recovery unlocks at {:?}.", unlock_time);
+            return Err(SampleError::RecoveryLocked.into());
+        }
+    }
+    msg!("Recovering wallet {} for {}", wallet_info.key, recovery.new_owner);
+    wallet_state.set_owner(recovery.new_owner);
+    // Delegates of the previous owner go with it, as do its pending
+    // withdrawals and subscriptions
+    let settings = wallet_state.wallet_settings_mut();
+    settings.session_keys.clear();
+    settings.allowances.clear();
+    settings.owner_epoch += 1;
+    wallet_info.realloc(wallet_state.packed_len()?, false)?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+/// Veto the recovery of the wallet, first in accounts, as its owner
+fn veto_recovery(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Veto recovery");
     let account_info_iter = &mut accounts.iter();
-    let program_account = next_account_info(account_info_iter)?;
-    let mut account_data = program_account.data.borrow_mut();
-    // Unpacking an uninitialized account state will fail
-    let mut account_state = ProgramAccountState::unpack(&account_data)?;
-    account_state.add(key, value)?;
-    ProgramAccountState::pack(account_state, &mut account_data)?;
-    Ok(())
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+
+    let mut wallet_state = check_wallet_owner_key(program_id, wallet_info, owner_info)?;
+    if wallet_state.wallet_settings_mut().recovery.take().is_none() {
+        return Err(SampleError::NoRecoveryPending.into());
+    }
+    wallet_info.realloc(wallet_state.packed_len()?, false)?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
 }
-/// Mint a key/value pair extracting a service fee for the effort
-fn mint_keypair_to_account_with_fee(
+/// Checks that the owner itself, rather than a session key, signed for the
+/// wallet, returning the wallet state
+fn check_wallet_owner_key(
+    program_id: &Pubkey,
+    wallet_info: &AccountInfo,
+    owner_info: &AccountInfo,
+) -> Result<ProgramAccountState, ProgramError> {
+    let wallet_state = check_wallet_owner(program_id, wallet_info, owner_info)?;
+    if wallet_state.owner() != owner_info.key {
+        msg!("Fail: Only the owner {} delegates to other signers.", wallet_state.owner());
+        return Err(ProgramError::IllegalOwner);
+    }
+    Ok(wallet_state)
+}
+/// Register the session key on the wallet, first in accounts, replacing the
+/// one of the same delegate. The owner follows and pays for the wallet to
+/// grow, ahead of the system program.
+fn add_session_key(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
-    key: String,
-    value: String,
+    session_key: SessionKey,
 ) -> ProgramResult {
-    // Charge for service
+    msg!("Add session key");
     let account_info_iter = &mut accounts.iter();
-    let program_account = next_account_info(account_info_iter)?;
-    let service_account = next_account_info(account_info_iter)?;
-    charge_service_fee(
-        program_account,
-        service_account,
-        SampleServiceFees::Minting as u64,
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    let mut wallet_state = check_wallet_owner_key(program_id, wallet_info, owner_info)?;
+    if session_key.key == *owner_info.key {
+        msg!("Fail: The owner can not be its own session key.");
+        return Err(SampleError::InvalidSessionKey.into());
+    }
+    let settings = wallet_state.wallet_settings_mut();
+    if let Some(registered) = settings.session_key_mut(&session_key.key) {
+        *registered = session_key;
+    } else if settings.session_keys.len() < MAX_SESSION_KEYS {
+        settings.session_keys.push(session_key);
+    } else {
+        msg!("Fail: The wallet holds {} session keys already.", MAX_SESSION_KEYS);
+        return Err(SampleError::InvalidSessionKey.into());
+    }
+    resize_account(
+        wallet_info,
+        owner_info,
+        system_program_info,
+        wallet_state.packed_len()?,
     )?;
-    // Invoke the actual mint
-    mint_keypair_to_account(accounts, key, value)?;
-    Ok(())
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
 }
-/// Transfer a key/pair from one program account to another
-/// "from" account is first and "to" account is second  in accounts
-fn transfer_keypair_to_account(accounts: &[AccountInfo], key: String) -> ProgramResult {
-    msg!("Transfer from account");
+/// Revoke the session key of the delegate from the wallet, first in
+/// accounts, as its owner following it
+fn revoke_session_key(program_id: &Pubkey, accounts: &[AccountInfo], key: Pubkey) -> ProgramResult {
+    msg!("Revoke session key");
     let account_info_iter = &mut accounts.iter();
-    // Transfer from this account
-    let from_program_account = next_account_info(account_info_iter)?;
-    let mut from_account_data = from_program_account.data.borrow_mut();
-    let mut from_account_state = ProgramAccountState::unpack(&from_account_data)?;
-    // To this account
-    let to_program_account = next_account_info(account_info_iter)?;
-    let mut to_account_data = to_program_account.data.borrow_mut();
-    let mut to_account_state = ProgramAccountState::unpack(&to_account_data)?;
-    // Transfer the goods
-    match from_account_state.remove(&key) {
-        Ok(value) => {
-            to_account_state.add(key, value)?;
-            ProgramAccountState::pack(from_account_state, &mut from_account_data)?;
-            ProgramAccountState::pack(to_account_state, &mut to_account_data)?;
-            Ok(())
-        }
-        Err(e) => Err(e.into()),
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+
+    let mut wallet_state = check_wallet_owner_key(program_id, wallet_info, owner_info)?;
+    let session_keys = &mut wallet_state.wallet_settings_mut().session_keys;
+    let count = session_keys.len();
+    session_keys.retain(|session_key| session_key.key != key);
+    if session_keys.len() == count {
+        msg!("Fail: {} is not a session key of the wallet.", key);
+        return Err(SampleError::InvalidSessionKey.into());
     }
+    wallet_info.realloc(wallet_state.packed_len()?, false)?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
 }
-/// Transfer key/value pair extracting a service fee for the effort
-fn transfer_keypair_to_account_with_fee(accounts: &[AccountInfo], key: String) -> ProgramResult {
+/// Subscribe the payee of the transfer out of the wallet, first in accounts,
+/// to collect it every period of seconds from the first due unix timestamp.
+/// The owner follows and pays for the subscription account next in
+/// accounts, ahead of the system program. Session keys can not subscribe,
+/// the payee collecting for good past their budget and expiry.
+fn create_subscription(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    action: ProposalAction,
+    period: i64,
+    next_due: i64,
+) -> ProgramResult {
+    msg!("Create subscription");
     let account_info_iter = &mut accounts.iter();
-    let from_account = next_account_info(account_info_iter)?;
-    let to_account = next_account_info(account_info_iter)?;
-    let service_account = next_account_info(account_info_iter)?;
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+    let subscription_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
 
-    // Cost to "from account"
-    charge_service_fee(
-        from_account,
-        service_account,
-        SampleServiceFees::Transfering as u64,
+    let mut wallet_state = check_wallet_owner_key(program_id, wallet_info, owner_info)?;
+    check_not_time_locked(wallet_info, &wallet_state)?;
+    if let ProposalAction::Invoke(_) = action {
+        msg!("Fail: Only transfers are subscribed to.");
+        return Err(SampleError::InvalidInstruction.into());
+    }
+    // The first payment is due at a unix timestamp from which the periods
+    // can be counted
+    if period <= 0 || next_due < 0 || next_due.checked_add(period).is_none() {
+        msg!("Fail: Payments every {} seconds from {} are out of range.", period, next_due);
+        return Err(SampleError::InvalidSubscription.into());
+    }
+    let settings = wallet_state.wallet_settings_mut();
+    let index = settings.subscription_count;
+    let (expected_subscription, bump_seed) =
+        find_subscription_address(program_id, wallet_info.key, index);
+    if expected_subscription != *subscription_info.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let subscription = Subscription {
+        wallet: *wallet_info.key,
+        action,
+        period,
+        next_due,
+        owner_epoch: settings.owner_epoch,
+    };
+    let space = subscription.packed_len()?;
+    let index_seed = index.to_le_bytes();
+    invoke_signed(
+        &system_instruction::create_account(
+            owner_info.key,
+            subscription_info.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[
+            owner_info.clone(),
+            subscription_info.clone(),
+            system_program_info.clone(),
+        ],
+        &[&[SUBSCRIPTION_SEED, wallet_info.key.as_ref(), &index_seed, &[bump_seed]]],
     )?;
-    // Cost to "to account"
-    charge_service_fee(
-        to_account,
-        service_account,
-        SampleServiceFees::Minting as u64,
+    subscription.pack_account(&mut subscription_info.try_borrow_mut_data()?)?;
+    msg!("Subscription {} is due at {}", index, next_due);
+
+    settings.subscription_count += 1;
+    resize_account(
+        wallet_info,
+        owner_info,
+        system_program_info,
+        wallet_state.packed_len()?,
     )?;
-    // Invoke the actual transfer
-    transfer_keypair_to_account(accounts, key)?;
-    Ok(())
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
 }
-/// Burn a key/pair from the programs account, which is the first in accounts
-fn burn_keypair_from_account(accounts: &[AccountInfo], key: String) -> ProgramResult {
-    msg!("Burn from account");
+/// Reads the subscription, which has to be one to the wallet
+fn load_subscription(
+    wallet_info: &AccountInfo,
+    subscription_info: &AccountInfo,
+) -> Result<Subscription, ProgramError> {
+    let subscription = Subscription::unpack_account(&subscription_info.try_borrow_data()?)?;
+    if subscription.wallet != *wallet_info.key {
+        msg!("Fail: The subscription is to the wallet {}.", subscription.wallet);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(subscription)
+}
+/// Collect the payment due of the subscription, second in accounts after the
+/// wallet, on behalf of anyone. The accounts of the transfer follow as
+/// `execute_action` takes them. Payments out of a wallet that took a
+/// withdrawal delay since are queued like any other transfer.
+fn collect_subscription(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Collect subscription");
     let account_info_iter = &mut accounts.iter();
-    let program_account = next_account_info(account_info_iter)?;
-    let mut account_data = program_account.data.borrow_mut();
-    let mut account_state = ProgramAccountState::unpack_unchecked(&account_data)?;
-    match account_state.remove(&key) {
-        Ok(_) => {
-            ProgramAccountState::pack(account_state, &mut account_data)?;
-            Ok(())
-        }
-        Err(e) => Err(e.into()),
+    let wallet_info = next_account_info(account_info_iter)?;
+    let subscription_info = next_account_info(account_info_iter)?;
+
+    let wallet_state = ProgramAccountState::unpack_account(&wallet_info.try_borrow_data()?)?;
+    check_account_type(wallet_info, &wallet_state, AccountType::Wallet)?;
+    let mut subscription = load_subscription(wallet_info, subscription_info)?;
+    check_owner_epoch(wallet_info, &wallet_state, subscription.owner_epoch)?;
+    check_not_time_locked(wallet_info, &wallet_state)?;
+    if let Err(error) = subscription.advance(Clock::get()?.unix_timestamp) {
+        msg!("Fail: The subscription is due at {}.", subscription.next_due);
+        return Err(error.into());
     }
+    subscription.pack_account(&mut subscription_info.try_borrow_mut_data()?)?;
+    execute_action(program_id, wallet_info, account_info_iter, subscription.action)
 }
-/// Burn a key/pair extracting a service fee for the effort
-fn burn_keypair_from_account_with_fee(accounts: &[AccountInfo], key: String) -> ProgramResult {
+/// Cancel the subscription, third in accounts after the wallet and its
+/// owner, returning the rent of the closed subscription account to the
+/// owner following it
+fn cancel_subscription(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    msg!("Cancel subscription");
     let account_info_iter = &mut accounts.iter();
-    let program_account = next_account_info(account_info_iter)?;
-    let service_account = next_account_info(account_info_iter)?;
-    // Charge for service
-    charge_service_fee(
-        program_account,
-        service_account,
-        SampleServiceFees::Burning as u64,
+    let wallet_info = next_account_info(account_info_iter)?;
+    let signer_info = next_account_info(account_info_iter)?;
+    let subscription_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+
+    let wallet_state = check_wallet_owner(program_id, wallet_info, signer_info)?;
+    if wallet_state.owner() != owner_info.key {
+        msg!("Fail: The wallet is owned by {}.", wallet_state.owner());
+        return Err(ProgramError::IllegalOwner);
+    }
+    load_subscription(wallet_info, subscription_info)?;
+    close_account(subscription_info, owner_info)
+}
+/// Allow the spender to transfer the amount of tokens of the mint out of the
+/// wallet, first in accounts, until the expiry unix timestamp, replacing its
+/// previous allowance. The owner follows and pays for the wallet to grow,
+/// ahead of the system program.
+fn approve_allowance(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    spender: Pubkey,
+    mint: Pubkey,
+    amount: u64,
+    expiry: i64,
+) -> ProgramResult {
+    msg!("Approve allowance");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    let mut wallet_state = check_wallet_owner_key(program_id, wallet_info, owner_info)?;
+    check_not_time_locked(wallet_info, &wallet_state)?;
+    let settings = wallet_state.wallet_settings_mut();
+    let allowance = Allowance {
+        spender,
+        mint,
+        amount,
+        expiry,
+    };
+    if let Some(approved) = settings.allowance_mut(&spender, &mint) {
+        *approved = allowance;
+    } else if settings.allowances.len() < MAX_ALLOWANCES {
+        settings.allowances.push(allowance);
+    } else {
+        msg!("Fail: The wallet holds {} allowances already.", MAX_ALLOWANCES);
+        return Err(SampleError::InvalidAllowance.into());
+    }
+    resize_account(
+        wallet_info,
+        owner_info,
+        system_program_info,
+        wallet_state.packed_len()?,
     )?;
-    // Invoke the actual burn
-    burn_keypair_from_account(accounts, key)?;
-    Ok(())
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+/// Revoke the allowance of the spender on the tokens of the mint from the
+/// wallet, first in accounts, as its owner following it
+fn revoke_allowance(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    spender: Pubkey,
+    mint: Pubkey,
+) -> ProgramResult {
+    msg!("Revoke allowance");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let owner_info = next_account_info(account_info_iter)?;
+
+    let mut wallet_state = check_wallet_owner_key(program_id, wallet_info, owner_info)?;
+    let allowances = &mut wallet_state.wallet_settings_mut().allowances;
+    let count = allowances.len();
+    allowances.retain(|allowance| allowance.spender != spender || allowance.mint != mint);
+    if allowances.len() == count {
+        msg!("Fail: {} has no allowance on {}.", spender, mint);
+        return Err(SampleError::InvalidAllowance.into());
+    }
+    wallet_info.realloc(wallet_state.packed_len()?, false)?;
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)
+}
+/// Transfer tokens out of the wallet, first in accounts, as the spender
+/// following it, taking the amount out of its allowance on the mint first.
+/// The accounts `send_wallet_tokens` takes follow. Time locked and multisig
+/// wallets hold spenders to the queue and approvals their owners go through.
+fn transfer_from(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    decimals: u8,
+) -> ProgramResult {
+    msg!("Transfer from");
+    let account_info_iter = &mut accounts.iter();
+    let wallet_info = next_account_info(account_info_iter)?;
+    let spender_info = next_account_info(account_info_iter)?;
+    // The token program checks the mint against the source account
+    let mint_info = account_info_iter
+        .clone()
+        .nth(1)
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+
+    let mut wallet_state = ProgramAccountState::unpack_account(&wallet_info.try_borrow_data()?)?;
+    check_account_type(wallet_info, &wallet_state, AccountType::Wallet)?;
+    check_not_multisig(wallet_info, &wallet_state)?;
+    check_not_time_locked(wallet_info, &wallet_state)?;
+    let allowance = match wallet_state
+        .wallet_settings_mut()
+        .allowance_mut(spender_info.key, mint_info.key)
+    {
+        Some(allowance) => allowance,
+        None => {
+            msg!("Fail: {} has no allowance on {}.", spender_info.key, mint_info.key);
+            return Err(SampleError::AllowanceExceeded.into());
+        }
+    };
+    if let Err(error) = allowance.spend(Clock::get()?.unix_timestamp, amount) {
+        msg!(
+            "Fail: {} is allowed {} until {}.",
+            spender_info.key,
+            allowance.amount,
+            allowance.expiry
+        );
+        return Err(error.into());
+    }
+    wallet_state.pack_account(&mut wallet_info.try_borrow_mut_data()?)?;
+    send_wallet_tokens(
+        program_id,
+        wallet_info,
+        account_info_iter,
+        amount,
+        decimals,
+        false,
+    )
 }
 /// Main processing entry point dispatches to specific
 /// instruction handlers
@@ -293,17 +2729,24 @@ pub fn process_instruction(
     instruction_data: &[u8],
 ) -> ProgramResult {
     msg!("Received process request");
-    
-    // Check the account for program relationship
-    if let Err(error) = check_account_ownership(program_id, accounts) {
-        return Err(error);
-    }
 
     // Unpack the inbound data, mapping instruction to appropriate structure
-    match ProgramInstruction::unpack(instruction_data)? {
-        ProgramInstruction::WalletNew => wallet_new(accounts),
-        ProgramInstruction::WalletTransferSpl => wallet_transfer_spl(program_id, accounts, instruction_data),
-        ProgramInstruction::WalletTransferLamports => wallet_transfer_lamports(accounts),
+    let instruction = ProgramInstruction::unpack(instruction_data)?;
+
+    // Check the accounts for program relationship, writability and signers
+    check_accounts(program_id, accounts, &instruction.account_constraints())?;
+
+    // Check the session key signing for a wallet owner may run the instruction
+    check_session_key(program_id, accounts, &instruction)?;
+
+    match instruction {
+        ProgramInstruction::WalletNew(index) => wallet_new(program_id, accounts, index),
+        ProgramInstruction::WalletTransferSpl(amount, decimals, create_destination) => {
+            wallet_transfer_spl(program_id, accounts, amount, decimals, create_destination)
+        }
+        ProgramInstruction::WalletTransferLamports(amount) => {
+            wallet_transfer_lamports(program_id, accounts, amount)
+        }
         ProgramInstruction::InitializeAccount => initialize_account(accounts),
         ProgramInstruction::MintToAccount(key, value) => {
             mint_keypair_to_account(accounts, key, value)
@@ -313,13 +2756,67 @@ pub fn process_instruction(
         }
         ProgramInstruction::BurnFromAccount(key) => burn_keypair_from_account(accounts, key),
         ProgramInstruction::MintToAccountWithFee(key, value) => {
-            mint_keypair_to_account_with_fee(accounts, key, value)
+            mint_keypair_to_account_with_fee(program_id, accounts, key, value)
         }
         ProgramInstruction::TransferBetweenAccountsWithFee(key) => {
-            transfer_keypair_to_account_with_fee(accounts, key)
+            transfer_keypair_to_account_with_fee(program_id, accounts, key)
         }
         ProgramInstruction::BurnFromAccountWithFee(key) => {
-            burn_keypair_from_account_with_fee(accounts, key)
+            burn_keypair_from_account_with_fee(program_id, accounts, key)
+        }
+        ProgramInstruction::MigrateAccount => migrate_account(accounts),
+        ProgramInstruction::InitializeConfig(fees) => initialize_config(program_id, accounts, fees),
+        ProgramInstruction::UpdateFees(fees) => update_fees(program_id, accounts, fees),
+        ProgramInstruction::RegisterReferrer => register_referrer(program_id, accounts),
+        ProgramInstruction::ClaimReferralRewards => claim_referral_rewards(program_id, accounts),
+        ProgramInstruction::SetFeeDiscount(payer, discount) => {
+            set_fee_discount(program_id, accounts, payer, discount)
+        }
+        ProgramInstruction::WalletNewMultisig(index, members, threshold) => {
+            wallet_new_multisig(program_id, accounts, index, members, threshold)
+        }
+        ProgramInstruction::ProposeTransfer(action) => propose_action(program_id, accounts, action),
+        ProgramInstruction::Approve => vote_on_proposal(accounts, true),
+        ProgramInstruction::Reject => vote_on_proposal(accounts, false),
+        ProgramInstruction::Execute => execute_proposal(program_id, accounts),
+        ProgramInstruction::ProposeInstruction(proposed) => {
+            propose_action(program_id, accounts, ProposalAction::Invoke(proposed))
+        }
+        ProgramInstruction::SetSpendingLimit(mint, amount, period) => {
+            set_spending_limit(program_id, accounts, mint, amount, period)
+        }
+        ProgramInstruction::SetGuardians(guardians, recovery_threshold) => {
+            set_guardians(program_id, accounts, guardians, recovery_threshold)
+        }
+        ProgramInstruction::SetWithdrawalDelay(delay, threshold) => {
+            set_withdrawal_delay(program_id, accounts, delay, threshold)
+        }
+        ProgramInstruction::QueueWithdrawal(action) => {
+            queue_withdrawal(program_id, accounts, action)
+        }
+        ProgramInstruction::ExecuteWithdrawal => execute_withdrawal(program_id, accounts),
+        ProgramInstruction::CancelWithdrawal => cancel_withdrawal(accounts),
+        ProgramInstruction::InitiateRecovery(new_owner) => initiate_recovery(accounts, new_owner),
+        ProgramInstruction::ApproveRecovery => approve_recovery(accounts),
+        ProgramInstruction::FinalizeRecovery => finalize_recovery(accounts),
+        ProgramInstruction::VetoRecovery => veto_recovery(program_id, accounts),
+        ProgramInstruction::AddSessionKey(session_key) => {
+            add_session_key(program_id, accounts, session_key)
+        }
+        ProgramInstruction::RevokeSessionKey(key) => revoke_session_key(program_id, accounts, key),
+        ProgramInstruction::CreateSubscription(action, period, next_due) => {
+            create_subscription(program_id, accounts, action, period, next_due)
+        }
+        ProgramInstruction::CollectSubscription => collect_subscription(program_id, accounts),
+        ProgramInstruction::CancelSubscription => cancel_subscription(program_id, accounts),
+        ProgramInstruction::ApproveAllowance(spender, mint, amount, expiry) => {
+            approve_allowance(program_id, accounts, spender, mint, amount, expiry)
+        }
+        ProgramInstruction::RevokeAllowance(spender, mint) => {
+            revoke_allowance(program_id, accounts, spender, mint)
+        }
+        ProgramInstruction::TransferFrom(amount, decimals) => {
+            transfer_from(program_id, accounts, amount, decimals)
         }
     }
 }
@@ -1,65 +1,3165 @@
 use app_wallet::{
-    instruction::ProgramInstruction, 
+    account_state::{
+        find_config_address, find_fee_tiers_address, find_proposal_address, find_referral_address,
+        find_subscription_address, find_wallet_address, find_wallet_authority_address,
+        find_withdrawal_address, Allowance, FeeSchedule, FeeShare, Multisig, ProgramAccountState,
+        ProgramConfig, ProposalAction, ProposedInstruction, ReferralAccount, SessionKey,
+        SpendingLimit, MAX_WITHDRAWAL_DELAY, RECOVERY_DELAY,
+    },
+    error::SampleError,
+    instruction::ProgramInstruction,
     processor::process_instruction
 };
 
 use {
+    borsh::BorshSerialize,
+    sol_template_shared::{
+        pack_into_slice, AccountType, ACCOUNT_STATE_HEADER, ACCOUNT_STATE_SPACE, BTREE_LENGTH,
+        DISCRIMINATOR_BYTES, LAYOUT_VERSION, VERSION_BYTES,
+    },
     solana_sdk::{
         account::Account,
+        bpf_loader_upgradeable::{self, UpgradeableLoaderState},
         pubkey::Pubkey,
-        signature::{Signer},
-        transaction::Transaction,
+        signature::{Keypair, Signer},
+        system_instruction, system_program,
+        transaction::{Transaction, TransactionError},
     },
     solana_program::{
-        instruction::{AccountMeta, Instruction},
+        account_info::AccountInfo,
+        clock::Clock,
+        entrypoint::MAX_PERMITTED_DATA_INCREASE,
+        instruction::{AccountMeta, Instruction, InstructionError},
+        program_error::ProgramError,
+        rent::Rent,
+    },
+    solana_program_test::{processor, tokio, BanksClient, ProgramTest},
+    std::collections::BTreeMap,
+    spl_associated_token_account::get_associated_token_address_with_program_id,
+    spl_token_2022::{
+        extension::{
+            memo_transfer::instruction::enable_required_transfer_memos,
+            transfer_fee::instruction::initialize_transfer_fee_config, ExtensionType,
+            StateWithExtensionsOwned,
+        },
+        state::{Account as TokenAccount, Mint},
     },
-    solana_program_test::{processor, tokio, ProgramTest},
 };
 
+fn wallet_new_instruction(program_id: &Pubkey, owner: &Pubkey, index: Option<u8>) -> Instruction {
+    let (wallet_pubkey, _) = find_wallet_address(program_id, owner, index);
+    Instruction::new_with_borsh(
+        *program_id,
+        &ProgramInstruction::WalletNew(index),
+        vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(wallet_pubkey, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Adds the program config account with the treasury and fee schedule
+fn add_config_account(
+    program_test: &mut ProgramTest,
+    program_id: &Pubkey,
+    treasury: &Pubkey,
+    fees: FeeSchedule,
+) {
+    let config = ProgramConfig {
+        admin: Pubkey::new_unique(),
+        fee_recipient: *treasury,
+        fees,
+    };
+    let mut data = vec![0; config.packed_len().unwrap()];
+    config.pack_account(&mut data).unwrap();
+    program_test.add_account(
+        find_config_address(program_id).0,
+        Account {
+            lamports: 1_000_000_000,
+            data,
+            owner: *program_id,
+            ..Account::default()
+        },
+    );
+}
+
+#[tokio::test]
+async fn test_wallet_new() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), Some(1));
+    let mut transaction = Transaction::new_with_payer(
+        &[wallet_new_instruction(&program_id, &payer.pubkey(), Some(1))],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let wallet_account = banks_client.get_account(wallet_pubkey).await.unwrap().unwrap();
+    assert_eq!(wallet_account.owner, program_id);
+    let wallet_state = ProgramAccountState::unpack_account(&wallet_account.data).unwrap();
+    assert_eq!(*wallet_state.owner(), payer.pubkey());
+
+    // Creating the same wallet twice fails
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[wallet_new_instruction(&program_id, &payer.pubkey(), Some(1))],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+}
+
 #[tokio::test]
 async fn test_lamport_transfer() {
     let program_id = Pubkey::new_unique();
-    let source_pubkey = Pubkey::new_unique();
     let destination_pubkey = Pubkey::new_unique();
+    let stranger = Keypair::new();
 
     let mut program_test = ProgramTest::new(
         "app_wallet",
         program_id,
         processor!(process_instruction),
     );
-    
+
     program_test.add_account(
-        source_pubkey,
+        destination_pubkey,
         Account {
-            lamports: 5,
-            owner: program_id, // Can only withdraw lamports from accounts owned by the program
+            lamports: 890_875,
             ..Account::default()
         },
     );
+    // 10% of a transfer, but at least a lamport, goes to the treasury
+    let treasury_pubkey = Pubkey::new_unique();
     program_test.add_account(
-        destination_pubkey,
+        treasury_pubkey,
         Account {
-            lamports: 890_875,
+            lamports: 890_880,
             ..Account::default()
         },
     );
+    add_config_account(
+        &mut program_test,
+        &program_id,
+        &treasury_pubkey,
+        FeeSchedule {
+            transfer_basis_points: 1_000,
+            transfer_fee_min: 1,
+            transfer_fee_max: 100,
+            ..FeeSchedule::default()
+        },
+    );
+    let (config_pubkey, _) = find_config_address(&program_id);
     let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
 
-    println!("{:?}", source_pubkey);
-    println!("{:?}", destination_pubkey);
-    println!("{:?}", payer.pubkey());
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+
+    // Create and fund the wallet above its rent exempt minimum
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            wallet_new_instruction(&program_id, &payer.pubkey(), None),
+            system_instruction::transfer(&payer.pubkey(), &wallet_pubkey, 5),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
 
+    // Only the wallet owner may move funds
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_borsh(
             program_id,
-            &ProgramInstruction::WalletTransferLamports,
+            &ProgramInstruction::WalletTransferLamports(5),
             vec![
-                AccountMeta::new(source_pubkey, false),
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(stranger.pubkey(), true),
+                AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &stranger], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+    let wallet_account = banks_client.get_account(wallet_pubkey).await.unwrap().unwrap();
+    let rent_exempt_minimum = banks_client
+        .get_rent()
+        .await
+        .unwrap()
+        .minimum_balance(wallet_account.data.len());
+    assert_eq!(wallet_account.lamports, rent_exempt_minimum + 5);
+    assert_eq!(banks_client.get_balance(destination_pubkey).await.unwrap(), 890_875);
+
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::WalletTransferLamports(5),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(payer.pubkey(), true),
+                AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let destination_account = banks_client.get_account(destination_pubkey).await.unwrap().unwrap();
+    assert_eq!(destination_account.lamports, 890_879);
+    let treasury_account = banks_client.get_account(treasury_pubkey).await.unwrap().unwrap();
+    assert_eq!(treasury_account.lamports, 890_881);
+    assert_eq!(banks_client.get_balance(wallet_pubkey).await.unwrap(), rent_exempt_minimum);
+
+    // The wallet is back at its rent exempt minimum
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::WalletTransferLamports(1),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(payer.pubkey(), true),
                 AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+    assert_eq!(banks_client.get_balance(wallet_pubkey).await.unwrap(), rent_exempt_minimum);
+}
+
+/// Creates a token account of the mint, sized for the given extensions
+async fn create_token_account(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    token_program_id: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    extension_types: &[ExtensionType],
+) -> Pubkey {
+    let account = Keypair::new();
+    let space = ExtensionType::get_account_len::<TokenAccount>(extension_types);
+    let rent = banks_client.get_rent().await.unwrap();
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &account.pubkey(),
+                rent.minimum_balance(space),
+                space as u64,
+                token_program_id,
+            ),
+            spl_token_2022::instruction::initialize_account(
+                token_program_id,
+                &account.pubkey(),
+                mint,
+                owner,
+            )
+            .unwrap(),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[payer, &account], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    account.pubkey()
+}
+
+/// Creates a mint without extensions, the payer being its authority
+async fn create_mint(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    token_program_id: &Pubkey,
+    decimals: u8,
+) -> Pubkey {
+    let mint = Keypair::new();
+    let space = ExtensionType::get_account_len::<Mint>(&[]);
+    let rent = banks_client.get_rent().await.unwrap();
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &mint.pubkey(),
+                rent.minimum_balance(space),
+                space as u64,
+                token_program_id,
+            ),
+            spl_token_2022::instruction::initialize_mint(
+                token_program_id,
+                &mint.pubkey(),
+                &payer.pubkey(),
+                None,
+                decimals,
+            )
+            .unwrap(),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[payer, &mint], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    mint.pubkey()
+}
+
+/// Sends tokens out of a wallet with either token program, optionally
+/// through a transfer fee mint, into a memo required destination or into
+/// an associated token account created on the fly
+async fn run_wallet_transfer_spl(
+    token_program_id: Pubkey,
+    transfer_fee_basis_points: Option<u16>,
+    memo_required: bool,
+    create_destination: bool,
+) -> u64 {
+    let program_id = Pubkey::new_unique();
+    let mint = Keypair::new();
+    let decimals = 2;
+    let treasury = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    add_config_account(&mut program_test, &program_id, &treasury, FeeSchedule::default());
+    let (config_pubkey, _) = find_config_address(&program_id);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let (authority_pubkey, _) = find_wallet_authority_address(&program_id, &wallet_pubkey);
+
+    // Create the wallet and the mint
+    let mut mint_extensions = vec![];
+    let mut account_extensions = vec![];
+    if transfer_fee_basis_points.is_some() {
+        mint_extensions.push(ExtensionType::TransferFeeConfig);
+        account_extensions.push(ExtensionType::TransferFeeAmount);
+    }
+    let space = ExtensionType::get_account_len::<Mint>(&mint_extensions);
+    let rent = banks_client.get_rent().await.unwrap();
+    let mut instructions = vec![
+        wallet_new_instruction(&program_id, &payer.pubkey(), None),
+        system_instruction::create_account(
+            &payer.pubkey(),
+            &mint.pubkey(),
+            rent.minimum_balance(space),
+            space as u64,
+            &token_program_id,
+        ),
+    ];
+    if let Some(basis_points) = transfer_fee_basis_points {
+        instructions.push(
+            initialize_transfer_fee_config(
+                &token_program_id,
+                &mint.pubkey(),
+                Some(&payer.pubkey()),
+                Some(&payer.pubkey()),
+                basis_points,
+                u64::MAX,
+            )
+            .unwrap(),
+        );
+    }
+    instructions.push(
+        spl_token_2022::instruction::initialize_mint(
+            &token_program_id,
+            &mint.pubkey(),
+            &payer.pubkey(),
+            None,
+            decimals,
+        )
+        .unwrap(),
+    );
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &mint], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The wallet authority holds the source, the payer the destination
+    let source_pubkey = create_token_account(
+        &mut banks_client,
+        &payer,
+        &token_program_id,
+        &mint.pubkey(),
+        &authority_pubkey,
+        &account_extensions,
+    )
+    .await;
+    let treasury_pubkey = create_token_account(
+        &mut banks_client,
+        &payer,
+        &token_program_id,
+        &mint.pubkey(),
+        &treasury,
+        &account_extensions,
+    )
+    .await;
+    if memo_required {
+        account_extensions.push(ExtensionType::MemoTransfer);
+    }
+    let recipient = Pubkey::new_unique();
+    let destination_pubkey = if create_destination {
+        get_associated_token_address_with_program_id(&recipient, &mint.pubkey(), &token_program_id)
+    } else {
+        create_token_account(
+            &mut banks_client,
+            &payer,
+            &token_program_id,
+            &mint.pubkey(),
+            &payer.pubkey(),
+            &account_extensions,
+        )
+        .await
+    };
+
+    let mut instructions = vec![
+        spl_token_2022::instruction::mint_to(
+            &token_program_id,
+            &mint.pubkey(),
+            &source_pubkey,
+            &payer.pubkey(),
+            &[],
+            1_000,
+        )
+        .unwrap(),
+        // The wallet pays the rent of a created destination
+        system_instruction::transfer(&payer.pubkey(), &wallet_pubkey, 10_000_000),
+    ];
+    if memo_required {
+        instructions.push(
+            enable_required_transfer_memos(
+                &token_program_id,
+                &destination_pubkey,
+                &payer.pubkey(),
+                &[],
+            )
+            .unwrap(),
+        );
+    }
+    let mut account_metas = vec![
+        AccountMeta::new(wallet_pubkey, false),
+        AccountMeta::new_readonly(payer.pubkey(), true),
+        AccountMeta::new(source_pubkey, false),
+        AccountMeta::new_readonly(mint.pubkey(), false),
+        AccountMeta::new(destination_pubkey, false),
+        AccountMeta::new(authority_pubkey, false),
+        AccountMeta::new_readonly(token_program_id, false),
+        AccountMeta::new_readonly(config_pubkey, false),
+        AccountMeta::new(treasury_pubkey, false),
+    ];
+    if create_destination {
+        account_metas.push(AccountMeta::new_readonly(recipient, false));
+        account_metas.push(AccountMeta::new_readonly(spl_associated_token_account::id(), false));
+        account_metas.push(AccountMeta::new_readonly(system_program::id(), false));
+    }
+    if memo_required {
+        account_metas.push(AccountMeta::new_readonly(spl_memo::id(), false));
+    }
+    instructions.push(Instruction::new_with_borsh(
+        program_id,
+        &ProgramInstruction::WalletTransferSpl(400, decimals, create_destination),
+        account_metas,
+    ));
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let source_account = banks_client.get_account(source_pubkey).await.unwrap().unwrap();
+    let source_state =
+        StateWithExtensionsOwned::<TokenAccount>::unpack(source_account.data).unwrap();
+    assert_eq!(source_state.base.amount, 600);
+
+    let destination_account = banks_client.get_account(destination_pubkey).await.unwrap().unwrap();
+    StateWithExtensionsOwned::<TokenAccount>::unpack(destination_account.data)
+        .unwrap()
+        .base
+        .amount
+}
+
+#[tokio::test]
+async fn test_wallet_transfer_spl_token() {
+    let received = run_wallet_transfer_spl(spl_token::id(), None, false, false).await;
+    assert_eq!(received, 400);
+}
+
+#[tokio::test]
+async fn test_wallet_transfer_spl_token_2022() {
+    let received = run_wallet_transfer_spl(spl_token_2022::id(), None, false, false).await;
+    assert_eq!(received, 400);
+}
+
+#[tokio::test]
+async fn test_wallet_transfer_spl_token_2022_transfer_fee() {
+    // 5% of 400 is withheld in the destination account
+    let received = run_wallet_transfer_spl(spl_token_2022::id(), Some(500), false, false).await;
+    assert_eq!(received, 380);
+}
+
+#[tokio::test]
+async fn test_wallet_transfer_spl_token_2022_memo_required() {
+    let received = run_wallet_transfer_spl(spl_token_2022::id(), None, true, false).await;
+    assert_eq!(received, 400);
+}
+
+#[tokio::test]
+async fn test_wallet_transfer_spl_create_destination() {
+    let received = run_wallet_transfer_spl(spl_token::id(), None, false, true).await;
+    assert_eq!(received, 400);
+    let received = run_wallet_transfer_spl(spl_token_2022::id(), Some(500), false, true).await;
+    assert_eq!(received, 380);
+}
+
+#[tokio::test]
+async fn test_wallet_transfer_spl_mismatches() {
+    let program_id = Pubkey::new_unique();
+    let token_program_id = spl_token::id();
+    let decimals = 2;
+    let treasury = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    add_config_account(&mut program_test, &program_id, &treasury, FeeSchedule::default());
+    let (config_pubkey, _) = find_config_address(&program_id);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let (authority_pubkey, _) = find_wallet_authority_address(&program_id, &wallet_pubkey);
+    let mut transaction = Transaction::new_with_payer(
+        &[wallet_new_instruction(&program_id, &payer.pubkey(), None)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The wallet holds tokens of the mint and an account of another mint
+    let mint = create_mint(&mut banks_client, &payer, &token_program_id, decimals).await;
+    let other_mint = create_mint(&mut banks_client, &payer, &token_program_id, decimals).await;
+    let mut token_accounts = vec![];
+    for (mint, owner) in [
+        (mint, authority_pubkey),
+        (other_mint, authority_pubkey),
+        (mint, payer.pubkey()),
+        (other_mint, payer.pubkey()),
+        (mint, treasury),
+    ] {
+        token_accounts.push(
+            create_token_account(
+                &mut banks_client,
+                &payer,
+                &token_program_id,
+                &mint,
+                &owner,
+                &[],
+            )
+            .await,
+        );
+    }
+    let (source_pubkey, other_source_pubkey) = (token_accounts[0], token_accounts[1]);
+    let (destination_pubkey, other_destination_pubkey) = (token_accounts[2], token_accounts[3]);
+    let treasury_pubkey = token_accounts[4];
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[spl_token_2022::instruction::mint_to(
+            &token_program_id,
+            &mint,
+            &source_pubkey,
+            &payer.pubkey(),
+            &[],
+            1_000,
+        )
+        .unwrap()],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Either token account of the other mint, or the wrong decimals, fail
+    let cases = [
+        (other_source_pubkey, destination_pubkey, decimals),
+        (source_pubkey, other_destination_pubkey, decimals),
+        (source_pubkey, destination_pubkey, decimals + 1),
+    ];
+    let errors = [
+        SampleError::SourceMintMismatch,
+        SampleError::DestinationMintMismatch,
+        SampleError::MintDecimalsMismatch,
+    ];
+    for ((source_pubkey, destination_pubkey, decimals), error) in cases.into_iter().zip(errors) {
+        let mut transaction = Transaction::new_with_payer(
+            &[Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::WalletTransferSpl(400, decimals, false),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new_readonly(payer.pubkey(), true),
+                    AccountMeta::new(source_pubkey, false),
+                    AccountMeta::new_readonly(mint, false),
+                    AccountMeta::new(destination_pubkey, false),
+                    AccountMeta::new(authority_pubkey, false),
+                    AccountMeta::new_readonly(token_program_id, false),
+                    AccountMeta::new_readonly(config_pubkey, false),
+                    AccountMeta::new(treasury_pubkey, false),
+                ],
+            )],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        let transaction_error = banks_client.process_transaction(transaction).await.unwrap_err();
+        assert_eq!(
+            transaction_error.unwrap(),
+            TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_migrate_legacy_account() {
+    let program_id = Pubkey::new_unique();
+    let legacy_pubkey = Pubkey::new_unique();
+    let legacy_wallet_pubkey = Pubkey::new_unique();
+    let wallet_owner = Pubkey::new_unique();
+
+    // Legacy accounts hold [initialized][data length][BTreeMap] in 1024 bytes
+    let mut btree_storage = BTreeMap::new();
+    btree_storage.insert("a1".to_string(), "b1".to_string());
+    let btree_data = btree_storage.try_to_vec().unwrap();
+    let mut legacy_data = vec![0; 1024];
+    legacy_data[0] = 1;
+    legacy_data[1..5].copy_from_slice(&(btree_data.len() as u32).to_le_bytes());
+    legacy_data[5..5 + btree_data.len()].copy_from_slice(&btree_data);
+    // Later ones, wallets among them, hold
+    // [initialized][owner][data length][BTreeMap] in 1056 bytes
+    let mut legacy_wallet_data = vec![0; 1056];
+    legacy_wallet_data[0] = 1;
+    legacy_wallet_data[1..33].copy_from_slice(wallet_owner.as_ref());
+    legacy_wallet_data[33..37].copy_from_slice(&(btree_data.len() as u32).to_le_bytes());
+    legacy_wallet_data[37..37 + btree_data.len()].copy_from_slice(&btree_data);
+
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    let legacy_accounts = [
+        (legacy_pubkey, legacy_data),
+        (legacy_wallet_pubkey, legacy_wallet_data),
+    ];
+    for (pubkey, data) in legacy_accounts {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 100_000_000,
+                data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+    }
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let migrate_instruction = |pubkey| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::MigrateAccount,
+            vec![
+                AccountMeta::new(pubkey, false),
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            migrate_instruction(legacy_pubkey),
+            migrate_instruction(legacy_wallet_pubkey),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    for (pubkey, account_type, owner) in [
+        (legacy_pubkey, AccountType::KeyValueStore, Pubkey::default()),
+        (legacy_wallet_pubkey, AccountType::Wallet, wallet_owner),
+    ] {
+        let migrated_account = banks_client.get_account(pubkey).await.unwrap().unwrap();
+        assert_eq!(
+            ProgramAccountState::layout_version(&migrated_account.data).unwrap(),
+            LAYOUT_VERSION
+        );
+        let mut migrated_state =
+            ProgramAccountState::unpack_account(&migrated_account.data).unwrap();
+        assert_eq!(migrated_state.account_type(), account_type);
+        assert_eq!(*migrated_state.owner(), owner);
+        assert_eq!(migrated_state.remove("a1").unwrap(), "b1");
+    }
+}
+
+#[tokio::test]
+async fn test_missing_accounts_fail_cleanly() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::InitializeAccount,
+            vec![],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys)
+    );
+}
+
+#[tokio::test]
+async fn test_malformed_instruction_fails_cleanly() {
+    let program_id = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // No instruction has this tag
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, &[u8::MAX], vec![])],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::DeserializationFailure as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_corrupt_account_fails_cleanly() {
+    let program_id = Pubkey::new_unique();
+    let mut btree_storage = BTreeMap::new();
+    btree_storage.insert("a1".to_string(), "b1".to_string());
+    let mut account_data = vec![0; ACCOUNT_STATE_SPACE];
+    pack_into_slice(
+        AccountType::KeyValueStore,
+        true,
+        &Pubkey::default(),
+        &btree_storage,
+        &[],
+        &mut account_data,
+    )
+    .unwrap();
+
+    // Each corruption maps to its own error
+    let mut bad_flag = account_data.clone();
+    bad_flag[DISCRIMINATOR_BYTES + VERSION_BYTES] = 2;
+    let mut bad_length = account_data.clone();
+    bad_length[ACCOUNT_STATE_HEADER - BTREE_LENGTH..ACCOUNT_STATE_HEADER]
+        .copy_from_slice(&u32::MAX.to_le_bytes());
+    let mut bad_version = account_data;
+    bad_version[DISCRIMINATOR_BYTES] = LAYOUT_VERSION + 1;
+    let cases = [bad_flag, bad_length, bad_version];
+    let errors = [
+        SampleError::InvalidAccountStateFlag,
+        SampleError::AccountStateLengthOutOfRange,
+        SampleError::UnsupportedAccountVersion,
+    ];
+
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    let account_pubkeys: Vec<Pubkey> = cases
+        .into_iter()
+        .map(|data| {
+            let account_pubkey = Pubkey::new_unique();
+            program_test.add_account(
+                account_pubkey,
+                Account {
+                    lamports: 100_000_000,
+                    data,
+                    owner: program_id,
+                    ..Account::default()
+                },
+            );
+            account_pubkey
+        })
+        .collect();
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    for (account_pubkey, error) in account_pubkeys.into_iter().zip(errors) {
+        let mut transaction = Transaction::new_with_payer(
+            &[Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::BurnFromAccount("a1".to_string()),
+                vec![AccountMeta::new(account_pubkey, false)],
+            )],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        let result = banks_client.process_transaction(transaction).await;
+        assert_eq!(
+            result.unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_key_value_account_resizing() {
+    let program_id = Pubkey::new_unique();
+    let program_account = Pubkey::new_unique();
+    let rent = Rent::default();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_account(
+        program_account,
+        Account {
+            lamports: rent.minimum_balance(ACCOUNT_STATE_SPACE),
+            data: vec![0; ACCOUNT_STATE_SPACE],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let mint_instruction = |key: &str| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::MintToAccount(key.to_string(), "v".repeat(600)),
+            vec![
+                AccountMeta::new(program_account, false),
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
             ],
+        )
+    };
+
+    // The initial space holds the first pair and one more
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::InitializeAccount,
+                vec![AccountMeta::new(program_account, false)],
+            ),
+            mint_instruction("k1"),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let account = banks_client.get_account(program_account).await.unwrap().unwrap();
+    assert_eq!(account.data.len(), ACCOUNT_STATE_SPACE);
+
+    // The next pair grows the account, the payer topping up its rent
+    let mut transaction =
+        Transaction::new_with_payer(&[mint_instruction("k2")], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let account = banks_client.get_account(program_account).await.unwrap().unwrap();
+    let account_state = ProgramAccountState::unpack_account(&account.data).unwrap();
+    let grown_len = account_state.packed_len().unwrap();
+    assert!(grown_len > ACCOUNT_STATE_SPACE);
+    assert_eq!(account.data.len(), grown_len);
+    assert_eq!(account.lamports, rent.minimum_balance(grown_len));
+
+    // Burning shrinks it again and leaves the lamports be
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::BurnFromAccount("k2".to_string()),
+            vec![AccountMeta::new(program_account, false)],
         )],
         Some(&payer.pubkey()),
     );
     transaction.sign(&[&payer], recent_blockhash);
     banks_client.process_transaction(transaction).await.unwrap();
+    let account = banks_client.get_account(program_account).await.unwrap().unwrap();
+    let account_state = ProgramAccountState::unpack_account(&account.data).unwrap();
+    assert_eq!(account.data.len(), account_state.packed_len().unwrap());
+    assert!(account.data.len() < ACCOUNT_STATE_SPACE);
+    assert_eq!(account.lamports, rent.minimum_balance(grown_len));
+}
+
+#[tokio::test]
+async fn test_transfer_between_resized_accounts() {
+    let program_id = Pubkey::new_unique();
+    let from_account = Pubkey::new_unique();
+    let to_account = Pubkey::new_unique();
+    let rent = Rent::default();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    for account in [from_account, to_account] {
+        program_test.add_account(
+            account,
+            Account {
+                lamports: rent.minimum_balance(ACCOUNT_STATE_SPACE),
+                data: vec![0; ACCOUNT_STATE_SPACE],
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+    }
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let initialize_instruction = |account: Pubkey| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::InitializeAccount,
+            vec![AccountMeta::new(account, false)],
+        )
+    };
+    let mint_instruction = |account: Pubkey, key: &str| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::MintToAccount(key.to_string(), "v".repeat(600)),
+            vec![
+                AccountMeta::new(account, false),
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    };
+    let transfer_instruction = |key: &str| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::TransferBetweenAccounts(key.to_string()),
+            vec![
+                AccountMeta::new(from_account, false),
+                AccountMeta::new(to_account, false),
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    };
+
+    // The "from" account grows past its initial space, the "to" account is
+    // left with room for a single pair more
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            initialize_instruction(from_account),
+            initialize_instruction(to_account),
+            mint_instruction(from_account, "k1"),
+            mint_instruction(from_account, "k2"),
+            mint_instruction(to_account, "k3"),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let account = banks_client.get_account(from_account).await.unwrap().unwrap();
+    let from_len = account.data.len();
+    assert!(from_len > ACCOUNT_STATE_SPACE);
+
+    // Both pairs fit the "to" account only once it grows
+    let mut transaction = Transaction::new_with_payer(
+        &[transfer_instruction("k1"), transfer_instruction("k2")],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let account = banks_client.get_account(to_account).await.unwrap().unwrap();
+    let mut account_state = ProgramAccountState::unpack_account(&account.data).unwrap();
+    let to_len = account_state.packed_len().unwrap();
+    assert!(to_len > ACCOUNT_STATE_SPACE);
+    assert_eq!(account.data.len(), to_len);
+    assert_eq!(account.lamports, rent.minimum_balance(to_len));
+    assert_eq!(account_state.remove("k1").unwrap(), "v".repeat(600));
+    assert_eq!(account_state.remove("k2").unwrap(), "v".repeat(600));
+
+    // The "from" account shrinks and keeps its lamports
+    let account = banks_client.get_account(from_account).await.unwrap().unwrap();
+    let mut account_state = ProgramAccountState::unpack_account(&account.data).unwrap();
+    assert_eq!(account.data.len(), account_state.packed_len().unwrap());
+    assert!(account_state.remove("k1").is_err());
+    assert!(account.data.len() < ACCOUNT_STATE_SPACE);
+    assert_eq!(account.lamports, rent.minimum_balance(from_len));
+}
+
+#[test]
+fn test_mint_past_data_increase_limit() {
+    // Transactions are too small to carry such a pair, so the processor is
+    // called directly
+    let program_id = Pubkey::new_unique();
+    let program_key = Pubkey::new_unique();
+    let payer_key = Pubkey::new_unique();
+    let system_program_key = system_program::id();
+    let mut account_state = ProgramAccountState::default();
+    account_state.set_initialized();
+    let mut program_data = vec![0; ACCOUNT_STATE_SPACE];
+    account_state.pack_account(&mut program_data).unwrap();
+    let (mut program_lamports, mut payer_lamports, mut system_lamports) = (0, 0, 0);
+    let (mut payer_data, mut system_data) = (vec![], vec![]);
+    let accounts = [
+        AccountInfo::new(
+            &program_key,
+            false,
+            true,
+            &mut program_lamports,
+            &mut program_data,
+            &program_id,
+            false,
+            0,
+        ),
+        AccountInfo::new(
+            &payer_key,
+            true,
+            true,
+            &mut payer_lamports,
+            &mut payer_data,
+            &system_program_key,
+            false,
+            0,
+        ),
+        AccountInfo::new(
+            &system_program_key,
+            false,
+            false,
+            &mut system_lamports,
+            &mut system_data,
+            &system_program_key,
+            true,
+            0,
+        ),
+    ];
+    let instruction = ProgramInstruction::MintToAccount(
+        "key".to_string(),
+        "v".repeat(MAX_PERMITTED_DATA_INCREASE),
+    );
+    assert_eq!(
+        process_instruction(&program_id, &accounts, &instruction.try_to_vec().unwrap()),
+        Err(ProgramError::Custom(SampleError::AccountStorageFull as u32))
+    );
+}
+
+fn config_instruction(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    fee_recipient: &Pubkey,
+    fees: FeeSchedule,
+) -> Instruction {
+    let (config_pubkey, _) = find_config_address(program_id);
+    let (program_data_pubkey, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    Instruction::new_with_borsh(
+        *program_id,
+        &ProgramInstruction::InitializeConfig(fees),
+        vec![
+            AccountMeta::new(*admin, true),
+            AccountMeta::new(config_pubkey, false),
+            AccountMeta::new_readonly(*fee_recipient, false),
+            AccountMeta::new_readonly(program_data_pubkey, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+fn mint_with_fee_instruction(
+    program_id: &Pubkey,
+    program_account: &Pubkey,
+    service_account: &Pubkey,
+    payer: &Pubkey,
+    key: &str,
+) -> Instruction {
+    let (config_pubkey, _) = find_config_address(program_id);
+    Instruction::new_with_borsh(
+        *program_id,
+        &ProgramInstruction::MintToAccountWithFee(key.to_string(), "value".to_string()),
+        vec![
+            AccountMeta::new(*program_account, false),
+            AccountMeta::new(*service_account, false),
+            AccountMeta::new_readonly(config_pubkey, false),
+            AccountMeta::new_readonly(find_fee_tiers_address(program_id).0, false),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+#[tokio::test]
+async fn test_config_fees() {
+    let program_id = Pubkey::new_unique();
+    let admin = Keypair::new();
+    let program_account = Pubkey::new_unique();
+    let service_account = Pubkey::new_unique();
+    let (config_pubkey, _) = find_config_address(&program_id);
+
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    // The admin is the upgrade authority recorded in the program data account
+    let (program_data_pubkey, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    program_test.add_account(
+        program_data_pubkey,
+        Account {
+            lamports: 1_000_000_000,
+            data: bincode::serialize(&UpgradeableLoaderState::ProgramData {
+                slot: 0,
+                upgrade_authority_address: Some(admin.pubkey()),
+            })
+            .unwrap(),
+            owner: bpf_loader_upgradeable::id(),
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        admin.pubkey(),
+        Account {
+            lamports: 1_000_000_000,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        program_account,
+        Account {
+            lamports: 1_000_000_000,
+            data: vec![0; ACCOUNT_STATE_SPACE],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        service_account,
+        Account {
+            lamports: 1_000_000,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let fees = FeeSchedule {
+        minting: 10,
+        transferring: 30,
+        burning: 15,
+        ..FeeSchedule::default()
+    };
+
+    // Only the upgrade authority sets the config up
+    let mut transaction = Transaction::new_with_payer(
+        &[config_instruction(
+            &program_id,
+            &payer.pubkey(),
+            &service_account,
+            fees.clone(),
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::InvalidConfigAdmin as u32)
+        )
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            config_instruction(&program_id, &admin.pubkey(), &service_account, fees),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::InitializeAccount,
+                vec![AccountMeta::new(program_account, false)],
+            ),
+            mint_with_fee_instruction(
+                &program_id,
+                &program_account,
+                &service_account,
+                &payer.pubkey(),
+                "a2",
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &admin], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let service_lamports = banks_client.get_balance(service_account).await.unwrap();
+    assert_eq!(service_lamports, 1_000_010);
+
+    // Fees only go to the treasury
+    let mut transaction = Transaction::new_with_payer(
+        &[mint_with_fee_instruction(
+            &program_id,
+            &program_account,
+            &program_account,
+            &payer.pubkey(),
+            "a4",
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::TreasuryMismatch as u32)
+        )
+    );
+
+    // Fees change without a redeploy, but only by the admin
+    let new_fees = FeeSchedule {
+        minting: 100,
+        transferring: 300,
+        burning: 150,
+        ..FeeSchedule::default()
+    };
+    let update_fees = |admin: &Pubkey, fees: FeeSchedule| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::UpdateFees(fees),
+            vec![
+                AccountMeta::new(*admin, true),
+                AccountMeta::new(config_pubkey, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[update_fees(&payer.pubkey(), new_fees.clone())],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            update_fees(&admin.pubkey(), new_fees),
+            mint_with_fee_instruction(
+                &program_id,
+                &program_account,
+                &service_account,
+                &payer.pubkey(),
+                "a3",
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &admin], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let service_lamports = banks_client.get_balance(service_account).await.unwrap();
+    assert_eq!(service_lamports, 1_000_110);
+
+    // A 50% discount halves the fee, a 100% one waives it
+    let set_fee_discount = |discount: u8| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::SetFeeDiscount(program_account, discount),
+            vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(find_fee_tiers_address(&program_id).0, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            set_fee_discount(50),
+            mint_with_fee_instruction(
+                &program_id,
+                &program_account,
+                &service_account,
+                &payer.pubkey(),
+                "a6",
+            ),
+            set_fee_discount(100),
+            mint_with_fee_instruction(
+                &program_id,
+                &program_account,
+                &service_account,
+                &payer.pubkey(),
+                "a7",
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &admin], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let service_lamports = banks_client.get_balance(service_account).await.unwrap();
+    assert_eq!(service_lamports, 1_000_160);
+
+    // With a fee mint the payer pays in tokens to the treasury token account
+    let token_program_id = spl_token::id();
+    let mint = Keypair::new();
+    let space = ExtensionType::get_account_len::<Mint>(&[]);
+    let rent = banks_client.get_rent().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &mint.pubkey(),
+                rent.minimum_balance(space),
+                space as u64,
+                &token_program_id,
+            ),
+            spl_token_2022::instruction::initialize_mint(
+                &token_program_id,
+                &mint.pubkey(),
+                &payer.pubkey(),
+                None,
+                2,
+            )
+            .unwrap(),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &mint], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let source_pubkey = create_token_account(
+        &mut banks_client,
+        &payer,
+        &token_program_id,
+        &mint.pubkey(),
+        &payer.pubkey(),
+        &[],
+    )
+    .await;
+    let treasury_pubkey = create_token_account(
+        &mut banks_client,
+        &payer,
+        &token_program_id,
+        &mint.pubkey(),
+        &service_account,
+        &[],
+    )
+    .await;
+
+    let token_fees = FeeSchedule {
+        minting: 25,
+        transferring: 50,
+        burning: 25,
+        mint: Some(mint.pubkey()),
+        ..FeeSchedule::default()
+    };
+    let mut mint_with_token_fee = mint_with_fee_instruction(
+        &program_id,
+        &program_account,
+        &service_account,
+        &payer.pubkey(),
+        "a5",
+    );
+    mint_with_token_fee.accounts.extend([
+        AccountMeta::new(source_pubkey, false),
+        AccountMeta::new_readonly(mint.pubkey(), false),
+        AccountMeta::new(treasury_pubkey, false),
+        AccountMeta::new_readonly(token_program_id, false),
+        AccountMeta::new_readonly(payer.pubkey(), true),
+    ]);
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            update_fees(&admin.pubkey(), token_fees),
+            spl_token_2022::instruction::mint_to(
+                &token_program_id,
+                &mint.pubkey(),
+                &source_pubkey,
+                &payer.pubkey(),
+                &[],
+                100,
+            )
+            .unwrap(),
+            mint_with_token_fee,
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &admin], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let treasury_account = banks_client.get_account(treasury_pubkey).await.unwrap().unwrap();
+    let treasury_account = StateWithExtensionsOwned::<TokenAccount>::unpack(treasury_account.data)
+        .unwrap();
+    assert_eq!(treasury_account.base.amount, 25);
+    let service_lamports = banks_client.get_balance(service_account).await.unwrap();
+    assert_eq!(service_lamports, 1_000_160);
+}
+
+#[tokio::test]
+async fn test_service_fee_insufficient_funds() {
+    let program_id = Pubkey::new_unique();
+    let program_account = Pubkey::new_unique();
+    let treasury = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    add_config_account(
+        &mut program_test,
+        &program_id,
+        &treasury,
+        FeeSchedule {
+            minting: 2_000_000_000,
+            ..FeeSchedule::default()
+        },
+    );
+    for pubkey in [program_account, treasury] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 1_000_000_000,
+                data: vec![0; ACCOUNT_STATE_SPACE],
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+    }
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // The program account can not afford the fee
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::InitializeAccount,
+                vec![AccountMeta::new(program_account, false)],
+            ),
+            mint_with_fee_instruction(
+                &program_id,
+                &program_account,
+                &treasury,
+                &payer.pubkey(),
+                "a2",
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            1,
+            InstructionError::Custom(SampleError::InsufficientFundsForTransaction as u32)
+        )
+    );
+    assert_eq!(banks_client.get_balance(treasury).await.unwrap(), 1_000_000_000);
+}
+
+#[tokio::test]
+async fn test_fee_shares() {
+    let program_id = Pubkey::new_unique();
+    let program_account = Pubkey::new_unique();
+    let treasury = Pubkey::new_unique();
+    let integrator = Pubkey::new_unique();
+    let referrer = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    add_config_account(
+        &mut program_test,
+        &program_id,
+        &treasury,
+        FeeSchedule {
+            minting: 10,
+            shares: vec![
+                FeeShare {
+                    recipient: integrator,
+                    basis_points: 3_333,
+                },
+                FeeShare {
+                    recipient: referrer,
+                    basis_points: 3_333,
+                },
+            ],
+            ..FeeSchedule::default()
+        },
+    );
+    program_test.add_account(
+        program_account,
+        Account {
+            lamports: 1_000_000_000,
+            data: vec![0; ACCOUNT_STATE_SPACE],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    for (recipient, owner) in [
+        (treasury, program_id),
+        (integrator, system_program::id()),
+        (referrer, system_program::id()),
+    ] {
+        program_test.add_account(
+            recipient,
+            Account {
+                lamports: 1_000_000,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let mut mint_with_fee =
+        mint_with_fee_instruction(&program_id, &program_account, &treasury, &payer.pubkey(), "a2");
+    mint_with_fee.accounts.extend([
+        AccountMeta::new(integrator, false),
+        AccountMeta::new(referrer, false),
+    ]);
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::InitializeAccount,
+                vec![AccountMeta::new(program_account, false)],
+            ),
+            mint_with_fee,
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Each share rounds down and the treasury keeps the remainder
+    assert_eq!(banks_client.get_balance(integrator).await.unwrap(), 1_000_003);
+    assert_eq!(banks_client.get_balance(referrer).await.unwrap(), 1_000_003);
+    assert_eq!(banks_client.get_balance(treasury).await.unwrap(), 1_000_004);
+}
+
+#[tokio::test]
+async fn test_referral_rewards() {
+    let program_id = Pubkey::new_unique();
+    let program_account = Pubkey::new_unique();
+    let treasury = Pubkey::new_unique();
+    let referrer = Keypair::new();
+    let (referral_pubkey, _) = find_referral_address(&program_id, &referrer.pubkey());
+
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    add_config_account(
+        &mut program_test,
+        &program_id,
+        &treasury,
+        FeeSchedule {
+            minting: 100,
+            referral_basis_points: 2_000,
+            ..FeeSchedule::default()
+        },
+    );
+    program_test.add_account(
+        program_account,
+        Account {
+            lamports: 1_000_000_000,
+            data: vec![0; ACCOUNT_STATE_SPACE],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        treasury,
+        Account {
+            lamports: 1_000_000,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        referrer.pubkey(),
+        Account {
+            lamports: 1_000_000_000,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let mut mint_with_fee =
+        mint_with_fee_instruction(&program_id, &program_account, &treasury, &payer.pubkey(), "a2");
+    mint_with_fee.accounts.push(AccountMeta::new(referral_pubkey, false));
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::RegisterReferrer,
+                vec![
+                    AccountMeta::new(referrer.pubkey(), true),
+                    AccountMeta::new(referral_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::InitializeAccount,
+                vec![AccountMeta::new(program_account, false)],
+            ),
+            mint_with_fee,
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &referrer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(banks_client.get_balance(treasury).await.unwrap(), 1_000_080);
+
+    // Whatever follows the fee accounts has to be a referral account
+    for not_referral in [program_account, referrer.pubkey()] {
+        let mut mint_with_fee = mint_with_fee_instruction(
+            &program_id,
+            &program_account,
+            &treasury,
+            &payer.pubkey(),
+            "a3",
+        );
+        mint_with_fee.accounts.push(AccountMeta::new(not_referral, false));
+        let mut transaction = Transaction::new_with_payer(&[mint_with_fee], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        let error = banks_client.process_transaction(transaction).await.unwrap_err();
+        assert_eq!(
+            error.unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(SampleError::AccountTypeMismatch as u32)
+            )
+        );
+    }
+
+    // Payers do not refer themselves
+    let (payer_referral_pubkey, _) = find_referral_address(&program_id, &payer.pubkey());
+    let mut mint_with_fee =
+        mint_with_fee_instruction(&program_id, &program_account, &treasury, &payer.pubkey(), "a3");
+    mint_with_fee.accounts.push(AccountMeta::new(payer_referral_pubkey, false));
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::RegisterReferrer,
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(payer_referral_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            mint_with_fee,
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            1,
+            InstructionError::Custom(SampleError::SelfReferral as u32)
+        )
+    );
+
+    // The referrer alone claims the 20% share
+    let destination = Pubkey::new_unique();
+    let claim = |referrer: &Pubkey| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::ClaimReferralRewards,
+            vec![
+                AccountMeta::new_readonly(*referrer, true),
+                AccountMeta::new(referral_pubkey, false),
+                AccountMeta::new(destination, false),
+            ],
+        )
+    };
+    let mut transaction =
+        Transaction::new_with_payer(&[claim(&payer.pubkey())], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+
+    let rent = banks_client.get_rent().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            system_instruction::transfer(&payer.pubkey(), &destination, rent.minimum_balance(0)),
+            claim(&referrer.pubkey()),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &referrer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        banks_client.get_balance(destination).await.unwrap(),
+        rent.minimum_balance(0) + 20
+    );
+}
+
+#[tokio::test]
+async fn test_account_type_mismatch() {
+    let program_id = Pubkey::new_unique();
+    let (config_pubkey, _) = find_config_address(&program_id);
+    let program_account = Pubkey::new_unique();
+    let treasury = Pubkey::new_unique();
+    let referral = ReferralAccount {
+        referrer: Pubkey::new_unique(),
+    };
+    let mut referral_data = vec![0; referral.packed_len().unwrap()];
+    referral.pack_account(&mut referral_data).unwrap();
+
+    // A referral account sits where the config belongs
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_account(
+        config_pubkey,
+        Account {
+            lamports: 1_000_000_000,
+            data: referral_data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    for pubkey in [program_account, treasury] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 1_000_000_000,
+                data: vec![0; ACCOUNT_STATE_SPACE],
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+    }
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // Neither the config nor the key/value store reads it
+    let instructions = [
+        mint_with_fee_instruction(
+            &program_id,
+            &program_account,
+            &treasury,
+            &payer.pubkey(),
+            "a1",
+        ),
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::BurnFromAccount("a1".to_string()),
+            vec![AccountMeta::new(config_pubkey, false)],
+        ),
+    ];
+    for instruction in instructions {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        let error = banks_client.process_transaction(transaction).await.unwrap_err();
+        assert_eq!(
+            error.unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(SampleError::AccountTypeMismatch as u32)
+            )
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_multisig_lamport_transfer() {
+    let program_id = Pubkey::new_unique();
+    let destination_pubkey = Pubkey::new_unique();
+    let treasury_pubkey = Pubkey::new_unique();
+    let second_member = Keypair::new();
+    let third_member = Keypair::new();
+
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    for pubkey in [destination_pubkey, treasury_pubkey] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 890_880,
+                ..Account::default()
+            },
+        );
+    }
+    add_config_account(&mut program_test, &program_id, &treasury_pubkey, FeeSchedule::default());
+    let (config_pubkey, _) = find_config_address(&program_id);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // Any two of the three members move the funds of the wallet
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let members = vec![payer.pubkey(), second_member.pubkey(), third_member.pubkey()];
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::WalletNewMultisig(None, members, 2),
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            system_instruction::transfer(&payer.pubkey(), &wallet_pubkey, 100),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The owner no longer transfers on its own
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::WalletTransferLamports(100),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(payer.pubkey(), true),
+                AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::MultisigApprovalRequired as u32)
+        )
+    );
+
+    // Proposing counts as the approval of the proposer
+    let (proposal_pubkey, _) = find_proposal_address(&program_id, &wallet_pubkey, 0);
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::ProposeTransfer(ProposalAction::TransferLamports {
+                destination: destination_pubkey,
+                amount: 100,
+            }),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new(proposal_pubkey, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let proposal_instruction = |instruction: ProgramInstruction, member: &Pubkey| {
+        Instruction::new_with_borsh(
+            program_id,
+            &instruction,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(*member, true),
+                AccountMeta::new(proposal_pubkey, false),
+                AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[proposal_instruction(ProgramInstruction::Execute, &third_member.pubkey())],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &third_member], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::ProposalThresholdNotMet as u32)
+        )
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            proposal_instruction(ProgramInstruction::Approve, &second_member.pubkey()),
+            proposal_instruction(ProgramInstruction::Execute, &second_member.pubkey()),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &second_member], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(banks_client.get_balance(destination_pubkey).await.unwrap(), 890_980);
+
+    // A proposal only executes once
+    let mut transaction = Transaction::new_with_payer(
+        &[proposal_instruction(ProgramInstruction::Execute, &payer.pubkey())],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::ProposalNotPending as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_multisig_invoke() {
+    let program_id = Pubkey::new_unique();
+    let destination_pubkey = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // A single member wallet whose authority holds lamports
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let (authority_pubkey, _) = find_wallet_authority_address(&program_id, &wallet_pubkey);
+    let rent = banks_client.get_rent().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::WalletNewMultisig(None, vec![payer.pubkey()], 1),
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            system_instruction::transfer(
+                &payer.pubkey(),
+                &authority_pubkey,
+                rent.minimum_balance(0) + 1_000,
+            ),
+            system_instruction::transfer(
+                &payer.pubkey(),
+                &destination_pubkey,
+                rent.minimum_balance(0),
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The members propose a system program transfer out of the authority
+    let proposed = system_instruction::transfer(&authority_pubkey, &destination_pubkey, 1_000);
+    let (proposal_pubkey, _) = find_proposal_address(&program_id, &wallet_pubkey, 0);
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::ProposeInstruction(ProposedInstruction::from(proposed)),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(proposal_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::Execute,
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new_readonly(payer.pubkey(), true),
+                    AccountMeta::new(proposal_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                    AccountMeta::new(authority_pubkey, false),
+                    AccountMeta::new(destination_pubkey, false),
+                ],
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        banks_client.get_balance(destination_pubkey).await.unwrap(),
+        rent.minimum_balance(0) + 1_000
+    );
+    assert_eq!(
+        banks_client.get_balance(authority_pubkey).await.unwrap(),
+        rent.minimum_balance(0)
+    );
+}
+
+#[tokio::test]
+async fn test_spending_limit() {
+    let program_id = Pubkey::new_unique();
+    let destination_pubkey = Pubkey::new_unique();
+    let treasury_pubkey = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    for pubkey in [destination_pubkey, treasury_pubkey] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 890_880,
+                ..Account::default()
+            },
+        );
+    }
+    add_config_account(&mut program_test, &program_id, &treasury_pubkey, FeeSchedule::default());
+    let (config_pubkey, _) = find_config_address(&program_id);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // The wallet sends at most 150 lamports a day
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            wallet_new_instruction(&program_id, &payer.pubkey(), None),
+            system_instruction::transfer(&payer.pubkey(), &wallet_pubkey, 1_000),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::SetSpendingLimit(None, 150, 86_400),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let transfer = |amount: u64| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::WalletTransferLamports(amount),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(payer.pubkey(), true),
+                AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(&[transfer(100)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(banks_client.get_balance(destination_pubkey).await.unwrap(), 890_980);
+
+    let mut transaction = Transaction::new_with_payer(&[transfer(51)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::SpendingLimitExceeded as u32)
+        )
+    );
+
+    // Multisig wallets take no spending limit, their members approving every
+    // transfer instead
+    let (multisig_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), Some(1));
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::WalletNewMultisig(Some(1), vec![payer.pubkey()], 1),
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(multisig_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::SetSpendingLimit(None, 150, 86_400),
+                vec![
+                    AccountMeta::new(multisig_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            1,
+            InstructionError::Custom(SampleError::MultisigApprovalRequired as u32)
+        )
+    );
+}
+
+#[test]
+fn test_spending_limit_rolling_window() {
+    // 150 lamports a day, regained at an even rate
+    let mut limit = SpendingLimit {
+        mint: None,
+        amount: 150,
+        period: 86_400,
+        ..SpendingLimit::default()
+    };
+    limit.spend(86_000, 150).unwrap();
+
+    // Past the end of the day the limit started, only what the seconds since
+    // the transfer are worth was regained
+    assert_eq!(limit.spend(86_800, 150), Err(SampleError::SpendingLimitExceeded));
+    assert_eq!(limit.spend(86_800, 1), Ok(()));
+    assert_eq!(limit.spend(86_800, 1), Err(SampleError::SpendingLimitExceeded));
+
+    // A lamport is regained every 576 seconds, the first one at 86_576 from
+    // which half the limit is back half a day later and all of it a day later
+    assert_eq!(limit.spend(86_576 + 43_200, 75), Ok(()));
+    assert_eq!(limit.spend(86_576 + 43_200, 1), Err(SampleError::SpendingLimitExceeded));
+    assert_eq!(limit.spend(86_576 + 43_200 + 86_400, 150), Ok(()));
+}
+
+#[tokio::test]
+async fn test_time_locked_withdrawal() {
+    let program_id = Pubkey::new_unique();
+    let destination_pubkey = Pubkey::new_unique();
+    let treasury_pubkey = Pubkey::new_unique();
+    let guardian = Keypair::new();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    for pubkey in [destination_pubkey, treasury_pubkey] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 890_880,
+                ..Account::default()
+            },
+        );
+    }
+    add_config_account(&mut program_test, &program_id, &treasury_pubkey, FeeSchedule::default());
+    let (config_pubkey, _) = find_config_address(&program_id);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // Withdrawals above 50 lamports wait an hour, in which the guardian can
+    // cancel them
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let wallet_settings_instruction = |instruction: ProgramInstruction| {
+        Instruction::new_with_borsh(
+            program_id,
+            &instruction,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            wallet_new_instruction(&program_id, &payer.pubkey(), None),
+            system_instruction::transfer(&payer.pubkey(), &wallet_pubkey, 1_000),
+            wallet_settings_instruction(ProgramInstruction::SetGuardians(
+                vec![guardian.pubkey()],
+                1,
+            )),
+            wallet_settings_instruction(ProgramInstruction::SetWithdrawalDelay(3_600, 50)),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let transfer_instruction = |amount: u64| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::WalletTransferLamports(amount),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(payer.pubkey(), true),
+                AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )
+    };
+    let mut transaction =
+        Transaction::new_with_payer(&[transfer_instruction(50)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let mut transaction =
+        Transaction::new_with_payer(&[transfer_instruction(100)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::WithdrawalDelayRequired as u32)
+        )
+    );
+
+    // The delay is capped, so that the wallet can not be frozen for good
+    let mut transaction = Transaction::new_with_payer(
+        &[wallet_settings_instruction(ProgramInstruction::SetWithdrawalDelay(
+            MAX_WITHDRAWAL_DELAY + 1,
+            50,
+        ))],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::InvalidWithdrawalDelay as u32)
+        )
+    );
+
+    // Lowering the delay waits out the current one
+    let mut transaction = Transaction::new_with_payer(
+        &[wallet_settings_instruction(ProgramInstruction::SetWithdrawalDelay(60, 50))],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let wallet_account = banks_client.get_account(wallet_pubkey).await.unwrap().unwrap();
+    let wallet_state = ProgramAccountState::unpack_account(&wallet_account.data).unwrap();
+    let settings = wallet_state.wallet_settings();
+    assert_eq!(settings.withdrawal_delay, 3_600);
+    let change = settings.withdrawal_policy_change.as_ref().unwrap();
+    assert_eq!(change.withdrawal_delay, 60);
+
+    // The transfer sets the transaction apart from the announcing one
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            system_instruction::transfer(&payer.pubkey(), &wallet_pubkey, 1),
+            wallet_settings_instruction(ProgramInstruction::SetWithdrawalDelay(60, 50)),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            1,
+            InstructionError::Custom(SampleError::WithdrawalLocked as u32)
+        )
+    );
+
+    let (withdrawal_pubkey, _) = find_withdrawal_address(&program_id, &wallet_pubkey, 0);
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::QueueWithdrawal(ProposalAction::TransferLamports {
+                destination: destination_pubkey,
+                amount: 100,
+            }),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new(withdrawal_pubkey, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The rent of the withdrawal goes back to the owner only
+    let execute_instruction = |refund: Pubkey| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::ExecuteWithdrawal,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(payer.pubkey(), true),
+                AccountMeta::new(withdrawal_pubkey, false),
+                AccountMeta::new(refund, false),
+                AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )
+    };
+    let cases = [destination_pubkey, payer.pubkey()];
+    let errors = [
+        InstructionError::IllegalOwner,
+        InstructionError::Custom(SampleError::WithdrawalLocked as u32),
+    ];
+    for (refund, error) in cases.into_iter().zip(errors) {
+        let mut transaction =
+            Transaction::new_with_payer(&[execute_instruction(refund)], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        let result = banks_client.process_transaction(transaction).await;
+        assert_eq!(result.unwrap_err().unwrap(), TransactionError::InstructionError(0, error));
+    }
+
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::CancelWithdrawal,
+            vec![
+                AccountMeta::new_readonly(wallet_pubkey, false),
+                AccountMeta::new_readonly(guardian.pubkey(), true),
+                AccountMeta::new(withdrawal_pubkey, false),
+                AccountMeta::new(payer.pubkey(), false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &guardian], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert!(banks_client.get_account(withdrawal_pubkey).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_social_recovery() {
+    let program_id = Pubkey::new_unique();
+    let guardian = Keypair::new();
+    let new_owner = Pubkey::new_unique();
+    let program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let recovery_instruction = |instruction: ProgramInstruction| {
+        Instruction::new_with_borsh(
+            program_id,
+            &instruction,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new(guardian.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            wallet_new_instruction(&program_id, &payer.pubkey(), None),
+            system_instruction::transfer(&payer.pubkey(), &guardian.pubkey(), 1_000_000_000),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::SetGuardians(vec![guardian.pubkey()], 1),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            recovery_instruction(ProgramInstruction::InitiateRecovery(new_owner)),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &guardian], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The owner has days to veto the approved recovery
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::FinalizeRecovery,
+            vec![AccountMeta::new(wallet_pubkey, false)],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::RecoveryLocked as u32)
+        )
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::VetoRecovery,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(payer.pubkey(), true),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let wallet_account = banks_client.get_account(wallet_pubkey).await.unwrap().unwrap();
+    let wallet_state = ProgramAccountState::unpack_account(&wallet_account.data).unwrap();
+    assert_eq!(wallet_state.owner(), &payer.pubkey());
+    assert_eq!(wallet_state.wallet_settings().recovery, None);
+}
+
+#[tokio::test]
+async fn test_finalize_recovery() {
+    let program_id = Pubkey::new_unique();
+    let guardian = Keypair::new();
+    let new_owner = Keypair::new();
+    let session = Pubkey::new_unique();
+    let payee_pubkey = Pubkey::new_unique();
+    let treasury_pubkey = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    add_config_account(&mut program_test, &program_id, &treasury_pubkey, FeeSchedule::default());
+    let (config_pubkey, _) = find_config_address(&program_id);
+    let mut context = program_test.start_with_context().await;
+    let payer_pubkey = context.payer.pubkey();
+
+    // The owner delegates, allows, queues and subscribes before the guardian
+    // recovers the wallet
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer_pubkey, None);
+    let (withdrawal_pubkey, _) = find_withdrawal_address(&program_id, &wallet_pubkey, 0);
+    let (subscription_pubkey, _) = find_subscription_address(&program_id, &wallet_pubkey, 0);
+    let transfer = ProposalAction::TransferLamports {
+        destination: payee_pubkey,
+        amount: 100,
+    };
+    let owner_instruction = |instruction: ProgramInstruction, pending: Option<Pubkey>| {
+        let mut accounts = vec![
+            AccountMeta::new(wallet_pubkey, false),
+            AccountMeta::new(payer_pubkey, true),
+        ];
+        accounts.extend(pending.map(|pubkey| AccountMeta::new(pubkey, false)));
+        accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+        Instruction::new_with_borsh(program_id, &instruction, accounts)
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            wallet_new_instruction(&program_id, &payer_pubkey, None),
+            system_instruction::transfer(&payer_pubkey, &wallet_pubkey, 1_000),
+            system_instruction::transfer(&payer_pubkey, &guardian.pubkey(), 1_000_000_000),
+            owner_instruction(
+                ProgramInstruction::SetGuardians(vec![guardian.pubkey()], 1),
+                None,
+            ),
+            owner_instruction(
+                ProgramInstruction::AddSessionKey(SessionKey {
+                    key: session,
+                    expiry_slot: u64::MAX,
+                    lamport_budget: 100,
+                    token_budget: 0,
+                    allowed_instructions: vec![],
+                }),
+                None,
+            ),
+            owner_instruction(
+                ProgramInstruction::ApproveAllowance(session, Pubkey::new_unique(), 100, i64::MAX),
+                None,
+            ),
+            owner_instruction(
+                ProgramInstruction::QueueWithdrawal(transfer.clone()),
+                Some(withdrawal_pubkey),
+            ),
+            owner_instruction(
+                ProgramInstruction::CreateSubscription(transfer, 86_400, 0),
+                Some(subscription_pubkey),
+            ),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::InitiateRecovery(new_owner.pubkey()),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(guardian.pubkey(), true),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+        ],
+        Some(&payer_pubkey),
+    );
+    transaction.sign(&[&context.payer, &guardian], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+
+    // Once the owner let the veto period pass, the new owner takes over
+    let mut clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    clock.unix_timestamp += RECOVERY_DELAY;
+    context.set_sysvar(&clock);
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::FinalizeRecovery,
+            vec![AccountMeta::new(wallet_pubkey, false)],
+        )],
+        Some(&payer_pubkey),
+    );
+    transaction.sign(&[&context.payer], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+
+    let wallet_account = context.banks_client.get_account(wallet_pubkey).await.unwrap().unwrap();
+    let wallet_state = ProgramAccountState::unpack_account(&wallet_account.data).unwrap();
+    let settings = wallet_state.wallet_settings();
+    assert_eq!(wallet_state.owner(), &new_owner.pubkey());
+    assert_eq!(settings.recovery, None);
+    assert!(settings.session_keys.is_empty());
+    assert!(settings.allowances.is_empty());
+    assert_eq!(wallet_account.data.len(), wallet_state.packed_len().unwrap());
+
+    // The withdrawal and subscription of the previous owner are void
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::ExecuteWithdrawal,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(new_owner.pubkey(), true),
+                AccountMeta::new(withdrawal_pubkey, false),
+                AccountMeta::new(new_owner.pubkey(), false),
+                AccountMeta::new(payee_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )],
+        Some(&payer_pubkey),
+    );
+    transaction.sign(&[&context.payer, &new_owner], context.last_blockhash);
+    let error = context.banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::OwnerChanged as u32)
+        )
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::CollectSubscription,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new(subscription_pubkey, false),
+                AccountMeta::new(payee_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )],
+        Some(&payer_pubkey),
+    );
+    transaction.sign(&[&context.payer], context.last_blockhash);
+    let error = context.banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::OwnerChanged as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_session_key() {
+    let program_id = Pubkey::new_unique();
+    let destination_pubkey = Pubkey::new_unique();
+    let treasury_pubkey = Pubkey::new_unique();
+    let session = Keypair::new();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    for pubkey in [destination_pubkey, treasury_pubkey] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 890_880,
+                ..Account::default()
+            },
+        );
+    }
+    add_config_account(&mut program_test, &program_id, &treasury_pubkey, FeeSchedule::default());
+    let (config_pubkey, _) = find_config_address(&program_id);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // The session key transfers up to 150 lamports, and nothing else
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            wallet_new_instruction(&program_id, &payer.pubkey(), None),
+            system_instruction::transfer(&payer.pubkey(), &wallet_pubkey, 1_000),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::AddSessionKey(SessionKey {
+                    key: session.pubkey(),
+                    expiry_slot: u64::MAX,
+                    lamport_budget: 150,
+                    token_budget: 0,
+                    allowed_instructions: vec![
+                        ProgramInstruction::WalletTransferLamports(0).tag().unwrap()
+                    ],
+                }),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let transfer = |amount: u64| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::WalletTransferLamports(amount),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(session.pubkey(), true),
+                AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(&[transfer(100)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &session], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(banks_client.get_balance(destination_pubkey).await.unwrap(), 890_980);
+
+    let mut transaction = Transaction::new_with_payer(&[transfer(51)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &session], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::SessionBudgetExceeded as u32)
+        )
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::SetWithdrawalDelay(3_600, 0),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new(session.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &session], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::SessionKeyNotAllowed as u32)
+        )
+    );
+
+    // Whatever the session key is allowed, only the owner changes the wallet
+    // settings, vetoes recoveries or subscribes, as every collection of a
+    // subscription within the budget would be charged to the wallet alone
+    let set_guardians = ProgramInstruction::SetGuardians(vec![destination_pubkey], 1);
+    let subscribe = ProgramInstruction::CreateSubscription(
+        ProposalAction::TransferLamports {
+            destination: destination_pubkey,
+            amount: 10,
+        },
+        86_400,
+        0,
+    );
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::AddSessionKey(SessionKey {
+                key: session.pubkey(),
+                expiry_slot: u64::MAX,
+                lamport_budget: 50,
+                token_budget: 0,
+                allowed_instructions: vec![
+                    set_guardians.tag().unwrap(),
+                    ProgramInstruction::VetoRecovery.tag().unwrap(),
+                    subscribe.tag().unwrap(),
+                ],
+            }),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let (subscription_pubkey, _) = find_subscription_address(&program_id, &wallet_pubkey, 0);
+    let session_instructions = [
+        Instruction::new_with_borsh(
+            program_id,
+            &set_guardians,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new(session.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        ),
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::VetoRecovery,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(session.pubkey(), true),
+            ],
+        ),
+        Instruction::new_with_borsh(
+            program_id,
+            &subscribe,
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new(session.pubkey(), true),
+                AccountMeta::new(subscription_pubkey, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        ),
+    ];
+    let errors = [
+        InstructionError::IllegalOwner,
+        InstructionError::IllegalOwner,
+        InstructionError::IllegalOwner,
+    ];
+    for (instruction, error) in session_instructions.into_iter().zip(errors) {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &session], recent_blockhash);
+        let result = banks_client.process_transaction(transaction).await;
+        assert_eq!(result.unwrap_err().unwrap(), TransactionError::InstructionError(0, error));
+    }
+}
+
+#[tokio::test]
+async fn test_subscription() {
+    let program_id = Pubkey::new_unique();
+    let payee_pubkey = Pubkey::new_unique();
+    let treasury_pubkey = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    for pubkey in [payee_pubkey, treasury_pubkey] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 890_880,
+                ..Account::default()
+            },
+        );
+    }
+    add_config_account(&mut program_test, &program_id, &treasury_pubkey, FeeSchedule::default());
+    let (config_pubkey, _) = find_config_address(&program_id);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // The payee collects 100 lamports a day, the first right away
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let (subscription_pubkey, _) = find_subscription_address(&program_id, &wallet_pubkey, 0);
+    let collect = Instruction::new_with_borsh(
+        program_id,
+        &ProgramInstruction::CollectSubscription,
+        vec![
+            AccountMeta::new(wallet_pubkey, false),
+            AccountMeta::new(subscription_pubkey, false),
+            AccountMeta::new(payee_pubkey, false),
+            AccountMeta::new_readonly(config_pubkey, false),
+            AccountMeta::new(treasury_pubkey, false),
+        ],
+    );
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            wallet_new_instruction(&program_id, &payer.pubkey(), None),
+            system_instruction::transfer(&payer.pubkey(), &wallet_pubkey, 1_000),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::CreateSubscription(
+                    ProposalAction::TransferLamports {
+                        destination: payee_pubkey,
+                        amount: 100,
+                    },
+                    86_400,
+                    0,
+                ),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(subscription_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            collect.clone(),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(banks_client.get_balance(payee_pubkey).await.unwrap(), 890_980);
+
+    let mut transaction = Transaction::new_with_payer(&[collect], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::SubscriptionNotDue as u32)
+        )
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::CancelSubscription,
+            vec![
+                AccountMeta::new_readonly(wallet_pubkey, false),
+                AccountMeta::new_readonly(payer.pubkey(), true),
+                AccountMeta::new(subscription_pubkey, false),
+                AccountMeta::new(payer.pubkey(), false),
+            ],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert!(banks_client.get_account(subscription_pubkey).await.unwrap().is_none());
+
+    // Periods and due times have to leave room to count periods
+    let (subscription_pubkey, _) = find_subscription_address(&program_id, &wallet_pubkey, 1);
+    for (period, next_due) in [(0, 0), (86_400, -1), (86_400, i64::MAX)] {
+        let mut transaction = Transaction::new_with_payer(
+            &[Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::CreateSubscription(
+                    ProposalAction::TransferLamports {
+                        destination: payee_pubkey,
+                        amount: 100,
+                    },
+                    period,
+                    next_due,
+                ),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(subscription_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            )],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        let error = banks_client.process_transaction(transaction).await.unwrap_err();
+        assert_eq!(
+            error.unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(SampleError::InvalidSubscription as u32)
+            )
+        );
+    }
+
+    // Payments wait in the queue like any transfer once the wallet took a
+    // withdrawal delay
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::CreateSubscription(
+                    ProposalAction::TransferLamports {
+                        destination: payee_pubkey,
+                        amount: 100,
+                    },
+                    86_400,
+                    0,
+                ),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(subscription_pubkey, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::SetWithdrawalDelay(3_600, 0),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::CollectSubscription,
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(subscription_pubkey, false),
+                    AccountMeta::new(payee_pubkey, false),
+                    AccountMeta::new_readonly(config_pubkey, false),
+                    AccountMeta::new(treasury_pubkey, false),
+                ],
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            2,
+            InstructionError::Custom(SampleError::WithdrawalDelayRequired as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_transfer_from_allowance() {
+    let program_id = Pubkey::new_unique();
+    let token_program_id = spl_token::id();
+    let mint = Keypair::new();
+    let decimals = 2;
+    let treasury = Pubkey::new_unique();
+    let spender = Keypair::new();
+    let mut program_test = ProgramTest::new(
+        "app_wallet",
+        program_id,
+        processor!(process_instruction),
+    );
+    add_config_account(&mut program_test, &program_id, &treasury, FeeSchedule::default());
+    let (config_pubkey, _) = find_config_address(&program_id);
+
+    // A multisig wallet with an allowance on record all the same
+    let multisig_wallet_pubkey = Pubkey::new_unique();
+    let mut multisig_wallet = ProgramAccountState::default();
+    multisig_wallet.set_initialized();
+    multisig_wallet.set_account_type(AccountType::Wallet);
+    multisig_wallet.set_owner(Pubkey::new_unique());
+    let settings = multisig_wallet.wallet_settings_mut();
+    settings.multisig = Some(Multisig {
+        members: vec![spender.pubkey()],
+        threshold: 1,
+        proposal_count: 0,
+    });
+    settings.allowances.push(Allowance {
+        spender: spender.pubkey(),
+        mint: mint.pubkey(),
+        amount: 500,
+        expiry: i64::MAX,
+    });
+    let mut multisig_wallet_data = vec![0; multisig_wallet.packed_len().unwrap()];
+    multisig_wallet.pack_account(&mut multisig_wallet_data).unwrap();
+    program_test.add_account(
+        multisig_wallet_pubkey,
+        Account {
+            lamports: 1_000_000_000,
+            data: multisig_wallet_data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (wallet_pubkey, _) = find_wallet_address(&program_id, &payer.pubkey(), None);
+    let (authority_pubkey, _) = find_wallet_authority_address(&program_id, &wallet_pubkey);
+    let space = ExtensionType::get_account_len::<Mint>(&[]);
+    let rent = banks_client.get_rent().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            wallet_new_instruction(&program_id, &payer.pubkey(), None),
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &mint.pubkey(),
+                rent.minimum_balance(space),
+                space as u64,
+                &token_program_id,
+            ),
+            spl_token_2022::instruction::initialize_mint(
+                &token_program_id,
+                &mint.pubkey(),
+                &payer.pubkey(),
+                None,
+                decimals,
+            )
+            .unwrap(),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &mint], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let mut token_accounts = vec![];
+    for owner in [authority_pubkey, treasury, payer.pubkey()] {
+        token_accounts.push(
+            create_token_account(
+                &mut banks_client,
+                &payer,
+                &token_program_id,
+                &mint.pubkey(),
+                &owner,
+                &[],
+            )
+            .await,
+        );
+    }
+    let (source_pubkey, treasury_pubkey, destination_pubkey) =
+        (token_accounts[0], token_accounts[1], token_accounts[2]);
+
+    // The spender may take 500 of the 1000 tokens the wallet holds
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            spl_token_2022::instruction::mint_to(
+                &token_program_id,
+                &mint.pubkey(),
+                &source_pubkey,
+                &payer.pubkey(),
+                &[],
+                1_000,
+            )
+            .unwrap(),
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::ApproveAllowance(
+                    spender.pubkey(),
+                    mint.pubkey(),
+                    500,
+                    i64::MAX,
+                ),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let transfer_from = |amount: u64| {
+        Instruction::new_with_borsh(
+            program_id,
+            &ProgramInstruction::TransferFrom(amount, decimals),
+            vec![
+                AccountMeta::new(wallet_pubkey, false),
+                AccountMeta::new_readonly(spender.pubkey(), true),
+                AccountMeta::new(source_pubkey, false),
+                AccountMeta::new_readonly(mint.pubkey(), false),
+                AccountMeta::new(destination_pubkey, false),
+                AccountMeta::new(authority_pubkey, false),
+                AccountMeta::new_readonly(token_program_id, false),
+                AccountMeta::new_readonly(config_pubkey, false),
+                AccountMeta::new(treasury_pubkey, false),
+            ],
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(&[transfer_from(400)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &spender], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let destination_account = banks_client.get_account(destination_pubkey).await.unwrap().unwrap();
+    let destination_state =
+        StateWithExtensionsOwned::<TokenAccount>::unpack(destination_account.data).unwrap();
+    assert_eq!(destination_state.base.amount, 400);
+
+    let mut transaction = Transaction::new_with_payer(&[transfer_from(101)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &spender], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::AllowanceExceeded as u32)
+        )
+    );
+
+    // Spenders go through the approvals of multisig wallets and the queue of
+    // time locked ones like their owners
+    let mut multisig_transfer_from = transfer_from(50);
+    multisig_transfer_from.accounts[0].pubkey = multisig_wallet_pubkey;
+    let mut transaction =
+        Transaction::new_with_payer(&[multisig_transfer_from], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &spender], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(SampleError::MultisigApprovalRequired as u32)
+        )
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_borsh(
+                program_id,
+                &ProgramInstruction::SetWithdrawalDelay(3_600, 0),
+                vec![
+                    AccountMeta::new(wallet_pubkey, false),
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            ),
+            transfer_from(50),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &spender], recent_blockhash);
+    let error = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            1,
+            InstructionError::Custom(SampleError::WithdrawalDelayRequired as u32)
+        )
+    );
 }
@@ -3,68 +3,683 @@
 use {
     arrayref::*,
     borsh::{BorshDeserialize, BorshSerialize},
-    solana_program::program_memory::sol_memcpy,
-    std::{collections::BTreeMap, error::Error},
+    solana_program::{program_memory::sol_memcpy, pubkey::Pubkey},
+    std::{collections::BTreeMap, error::Error, fmt},
 };
 
+/// Errors packing or unpacking the account state
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StateError {
+    /// The initialization flag is neither 0 nor 1
+    InvalidInitializedFlag(u8),
+    /// The account or its stored data length does not fit the account
+    LengthOutOfRange { len: usize, capacity: usize },
+    /// The BTreeMap container failed to (de)serialize
+    BorshFailure(String),
+    /// The serialized BTreeMap container does not fit the account
+    Overflow { len: usize, capacity: usize },
+    /// The account does not start with the account state discriminator
+    InvalidDiscriminator,
+    /// The layout version is newer than this program knows
+    UnsupportedVersion(u8),
+    /// The account holds another type of state than the one being read
+    UnexpectedAccountType(AccountType),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::InvalidInitializedFlag(flag) => {
+                write!(f, "unrecognized initialization flag \"{}\". in account", flag)
+            }
+            StateError::LengthOutOfRange { len, capacity } => {
+                write!(f, "length {} out of range, account holds {}", len, capacity)
+            }
+            StateError::BorshFailure(error) => write!(f, "borsh failure: {}", error),
+            StateError::Overflow { len, capacity } => {
+                write!(f, "{} bytes overflow the {} bytes of storage", len, capacity)
+            }
+            StateError::InvalidDiscriminator => f.write_str("unrecognized account discriminator"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported layout version {}", version)
+            }
+            StateError::UnexpectedAccountType(account_type) => {
+                write!(f, "unexpected {} account", account_type)
+            }
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// Storage for the discriminator marking versioned account state
+pub const DISCRIMINATOR_BYTES: usize = 8;
+/// Storage for the layout version of the account state
+pub const VERSION_BYTES: usize = 1;
 /// Initialization flag size for account state
 pub const INITIALIZED_BYTES: usize = 1;
+/// Storage for the public key of the account owner
+pub const OWNER_BYTES: usize = 32;
 /// Storage for the serialized size of the BTreeMap control
 pub const BTREE_LENGTH: usize = 4;
-/// Storage for the serialized BTreeMap container
+/// Storage for the size of the account extension following the BTreeMap
+pub const EXTENSION_LENGTH: usize = 4;
+/// Initial storage for the serialized BTreeMap container, accounts
+/// grow or shrink from there as the container changes
 pub const BTREE_STORAGE: usize = 1019;
-/// Sum of all account state lengths
-pub const ACCOUNT_STATE_SPACE: usize = INITIALIZED_BYTES + BTREE_LENGTH + BTREE_STORAGE;
+/// Fixed part of the account state preceding the BTreeMap container
+pub const ACCOUNT_STATE_HEADER: usize =
+    DISCRIMINATOR_BYTES + VERSION_BYTES + INITIALIZED_BYTES + OWNER_BYTES + BTREE_LENGTH;
+/// Initial size of an account holding the account state
+pub const ACCOUNT_STATE_SPACE: usize = ACCOUNT_STATE_HEADER + BTREE_STORAGE;
 
-/// Unpacks the data from slice and return the initialized flag and data content
-#[allow(clippy::ptr_offset_with_cast)]
-pub fn unpack_from_slice(src: &[u8]) -> Result<(bool, BTreeMap<String, String>), Box<dyn Error>> {
-    let src = array_ref![src, 0, ACCOUNT_STATE_SPACE];
-    // Setup pointers to key areas of account state data
-    let (is_initialized_src, data_len_src, data_src) =
-        array_refs![src, INITIALIZED_BYTES, BTREE_LENGTH, BTREE_STORAGE];
-
-    let is_initialized = match is_initialized_src {
-        [0] => false,
-        [1] => true,
-        _ => {
-            return Err(Box::<dyn Error>::from(format!(
-                "unrecognized initialization flag \"{:?}\". in account",
-                is_initialized_src
-            )))
+/// Discriminator of key/value store accounts. Like every discriminator its
+/// first byte can not be taken for the initialization flag of the legacy
+/// layout.
+pub const KEY_VALUE_STORE_DISCRIMINATOR: [u8; DISCRIMINATOR_BYTES] = *b"kvstore\0";
+/// Discriminator of wallet accounts
+pub const WALLET_DISCRIMINATOR: [u8; DISCRIMINATOR_BYTES] = *b"wallet\0\0";
+/// Discriminator of the program config account
+pub const CONFIG_DISCRIMINATOR: [u8; DISCRIMINATOR_BYTES] = *b"config\0\0";
+/// Discriminator of referral accounts
+pub const REFERRAL_DISCRIMINATOR: [u8; DISCRIMINATOR_BYTES] = *b"referral";
+/// Discriminator of the fee tiers account
+pub const FEE_TIERS_DISCRIMINATOR: [u8; DISCRIMINATOR_BYTES] = *b"feetiers";
+/// Discriminator of multisig proposal accounts
+pub const PROPOSAL_DISCRIMINATOR: [u8; DISCRIMINATOR_BYTES] = *b"proposal";
+/// Discriminator of pending withdrawal accounts
+pub const WITHDRAWAL_DISCRIMINATOR: [u8; DISCRIMINATOR_BYTES] = *b"withdraw";
+/// Discriminator of subscription accounts
+pub const SUBSCRIPTION_DISCRIMINATOR: [u8; DISCRIMINATOR_BYTES] = *b"subscrip";
+/// Discriminator of layout version 1, which did not tell account types apart
+pub const ACCOUNT_STATE_DISCRIMINATOR: [u8; DISCRIMINATOR_BYTES] = *b"appstate";
+/// Version of the layouts without discriminator or version: the original
+/// one, `[initialized][data length][BTreeMap]`, which has no owner, and the
+/// one adding the owner to it, `[initialized][owner][data length][BTreeMap]`
+pub const LEGACY_LAYOUT_VERSION: u8 = 0;
+/// Version of the layout with a common discriminator,
+/// `[discriminator][version][initialized][owner][data length][BTreeMap]`
+pub const UNTYPED_LAYOUT_VERSION: u8 = 1;
+/// Version of the layout starting with the discriminator of the account
+/// type instead of the common one
+pub const TYPED_LAYOUT_VERSION: u8 = 2;
+/// Version of the layout written by `pack_into_slice`, the version 2 layout
+/// followed by `[extension length][extension]`, an opaque extension of the
+/// state left for the program to interpret
+pub const LAYOUT_VERSION: u8 = 3;
+/// Discriminator and version heading the accounts without an account state,
+/// which follow it with their own borsh serialized content
+pub const TYPED_HEADER: usize = DISCRIMINATOR_BYTES + VERSION_BYTES;
+
+/// Type of the program owned account, told apart by its discriminator
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AccountType {
+    /// Key/value store set up by `InitializeAccount`
+    #[default]
+    KeyValueStore,
+    /// Program derived wallet created by `WalletNew`
+    Wallet,
+    /// Program config created by `InitializeConfig`
+    Config,
+    /// Referral account created by `RegisterReferrer`
+    Referral,
+    /// Fee discounts maintained by `SetFeeDiscount`
+    FeeTiers,
+    /// Multisig wallet proposal created by `ProposeTransfer`
+    Proposal,
+    /// Time-locked withdrawal queued by `QueueWithdrawal`
+    Withdrawal,
+    /// Recurring payment created by `CreateSubscription`
+    Subscription,
+}
+
+impl AccountType {
+    /// Returns the discriminator starting accounts of the type
+    pub fn discriminator(&self) -> [u8; DISCRIMINATOR_BYTES] {
+        match self {
+            AccountType::KeyValueStore => KEY_VALUE_STORE_DISCRIMINATOR,
+            AccountType::Wallet => WALLET_DISCRIMINATOR,
+            AccountType::Config => CONFIG_DISCRIMINATOR,
+            AccountType::Referral => REFERRAL_DISCRIMINATOR,
+            AccountType::FeeTiers => FEE_TIERS_DISCRIMINATOR,
+            AccountType::Proposal => PROPOSAL_DISCRIMINATOR,
+            AccountType::Withdrawal => WITHDRAWAL_DISCRIMINATOR,
+            AccountType::Subscription => SUBSCRIPTION_DISCRIMINATOR,
         }
-    };
-    // Get current size of content in data area
+    }
+
+    /// Returns the account type the discriminator belongs to
+    pub fn from_discriminator(discriminator: &[u8]) -> Option<Self> {
+        [
+            AccountType::KeyValueStore,
+            AccountType::Wallet,
+            AccountType::Config,
+            AccountType::Referral,
+            AccountType::FeeTiers,
+            AccountType::Proposal,
+            AccountType::Withdrawal,
+            AccountType::Subscription,
+        ]
+        .into_iter()
+        .find(|account_type| account_type.discriminator() == discriminator)
+    }
+
+    /// Tells whether the account type is held in the account state layout
+    pub fn has_account_state(&self) -> bool {
+        matches!(self, AccountType::KeyValueStore | AccountType::Wallet)
+    }
+}
+
+impl fmt::Display for AccountType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountType::KeyValueStore => f.write_str("key/value store"),
+            AccountType::Wallet => f.write_str("wallet"),
+            AccountType::Config => f.write_str("config"),
+            AccountType::Referral => f.write_str("referral"),
+            AccountType::FeeTiers => f.write_str("fee tiers"),
+            AccountType::Proposal => f.write_str("proposal"),
+            AccountType::Withdrawal => f.write_str("pending withdrawal"),
+            AccountType::Subscription => f.write_str("subscription"),
+        }
+    }
+}
+
+/// Returns the account size needed to hold the data content and an
+/// extension of the given length, computed without serializing the BTreeMap
+pub fn packed_len(btree_storage: &BTreeMap<String, String>, extension_len: usize) -> usize {
+    btree_storage.iter().fold(
+        ACCOUNT_STATE_HEADER + BTREE_LENGTH + EXTENSION_LENGTH + extension_len,
+        |len, (key, value)| len + BTREE_LENGTH + key.len() + BTREE_LENGTH + value.len(),
+    )
+}
+
+/// Returns the layout version of the account state in the slice
+pub fn layout_version(src: &[u8]) -> Result<u8, StateError> {
+    match src.first() {
+        None => Err(StateError::LengthOutOfRange {
+            len: INITIALIZED_BYTES,
+            capacity: 0,
+        }),
+        // Legacy state starts with its initialization flag
+        Some(0) | Some(1) => Ok(LEGACY_LAYOUT_VERSION),
+        Some(_) => {
+            if src.len() < DISCRIMINATOR_BYTES + VERSION_BYTES {
+                return Err(StateError::LengthOutOfRange {
+                    len: DISCRIMINATOR_BYTES + VERSION_BYTES,
+                    capacity: src.len(),
+                });
+            }
+            let discriminator = &src[..DISCRIMINATOR_BYTES];
+            if discriminator != ACCOUNT_STATE_DISCRIMINATOR {
+                match AccountType::from_discriminator(discriminator) {
+                    Some(account_type) if !account_type.has_account_state() => {
+                        return Err(StateError::UnexpectedAccountType(account_type))
+                    }
+                    Some(_) => (),
+                    None => return Err(StateError::InvalidDiscriminator),
+                }
+            }
+            Ok(src[DISCRIMINATOR_BYTES])
+        }
+    }
+}
+
+/// Unpacks the data from slice and return the account type, initialized flag,
+/// owner, data content and extension, reading any layout version the program
+/// ever wrote. Layouts predating the extension read with an empty one.
+#[allow(clippy::type_complexity)]
+pub fn unpack_from_slice(
+    src: &[u8],
+) -> Result<(AccountType, bool, Pubkey, BTreeMap<String, String>, Vec<u8>), StateError> {
+    match layout_version(src)? {
+        // Wallets were only introduced along with owners, which they always
+        // had while key/value stores never did
+        LEGACY_LAYOUT_VERSION => {
+            let (is_initialized, owner, btree_storage) = unpack_legacy(src)?;
+            let account_type = match owner == Pubkey::default() {
+                true => AccountType::KeyValueStore,
+                false => AccountType::Wallet,
+            };
+            Ok((account_type, is_initialized, owner, btree_storage, Vec::new()))
+        }
+        UNTYPED_LAYOUT_VERSION => {
+            let (_, is_initialized, owner, btree_storage, _) = unpack_versioned(src)?;
+            let account_type = match owner == Pubkey::default() {
+                true => AccountType::KeyValueStore,
+                false => AccountType::Wallet,
+            };
+            Ok((account_type, is_initialized, owner, btree_storage, Vec::new()))
+        }
+        TYPED_LAYOUT_VERSION => {
+            let (discriminator, is_initialized, owner, btree_storage, _) = unpack_versioned(src)?;
+            let account_type = AccountType::from_discriminator(discriminator)
+                .ok_or(StateError::InvalidDiscriminator)?;
+            Ok((account_type, is_initialized, owner, btree_storage, Vec::new()))
+        }
+        LAYOUT_VERSION => {
+            let (discriminator, is_initialized, owner, btree_storage, extension_src) =
+                unpack_versioned(src)?;
+            let account_type = AccountType::from_discriminator(discriminator)
+                .ok_or(StateError::InvalidDiscriminator)?;
+            let extension = unpack_extension(extension_src)?;
+            Ok((account_type, is_initialized, owner, btree_storage, extension))
+        }
+        version => Err(StateError::UnsupportedVersion(version)),
+    }
+}
+
+/// Reads the legacy layouts, taking the account for one with an owner when
+/// it reads as such, and for one without an owner otherwise
+#[allow(clippy::ptr_offset_with_cast, clippy::type_complexity)]
+fn unpack_legacy(src: &[u8]) -> Result<(bool, Pubkey, BTreeMap<String, String>), StateError> {
+    if let Some(legacy_state) = unpack_legacy_with_owner(src) {
+        return Ok(legacy_state);
+    }
+    const LEGACY_HEADER: usize = INITIALIZED_BYTES + BTREE_LENGTH;
+    if src.len() < LEGACY_HEADER {
+        return Err(StateError::LengthOutOfRange {
+            len: LEGACY_HEADER,
+            capacity: src.len(),
+        });
+    }
+    let (header_src, data_src) = src.split_at(LEGACY_HEADER);
+    let header_src = array_ref![header_src, 0, LEGACY_HEADER];
+    let (is_initialized_src, data_len_src) =
+        array_refs![header_src, INITIALIZED_BYTES, BTREE_LENGTH];
+    let is_initialized = unpack_initialized(is_initialized_src)?;
+    let btree_storage = unpack_btree(data_len_src, data_src)?;
+    Ok((is_initialized, Pubkey::default(), btree_storage))
+}
+
+/// Reads the legacy layout with an owner. Accounts without an owner never
+/// left their fixed size of 1024 bytes, while those with one either kept
+/// their fixed size of 1056 bytes or were sized to their content, which
+/// tells the layouts apart.
+#[allow(clippy::ptr_offset_with_cast, clippy::type_complexity)]
+fn unpack_legacy_with_owner(src: &[u8]) -> Option<(bool, Pubkey, BTreeMap<String, String>)> {
+    const LEGACY_HEADER: usize = INITIALIZED_BYTES + OWNER_BYTES + BTREE_LENGTH;
+    const LEGACY_SPACE: usize = LEGACY_HEADER + BTREE_STORAGE;
+    if src.len() < LEGACY_HEADER {
+        return None;
+    }
+    let (header_src, data_src) = src.split_at(LEGACY_HEADER);
+    let header_src = array_ref![header_src, 0, LEGACY_HEADER];
+    let (is_initialized_src, owner_src, data_len_src) =
+        array_refs![header_src, INITIALIZED_BYTES, OWNER_BYTES, BTREE_LENGTH];
+    let data_len = u32::from_le_bytes(*data_len_src) as usize;
+    if src.len() != LEGACY_SPACE && data_len != data_src.len() {
+        return None;
+    }
+    let is_initialized = unpack_initialized(is_initialized_src).ok()?;
+    let btree_storage = unpack_btree(data_len_src, data_src).ok()?;
+    Some((is_initialized, Pubkey::new_from_array(*owner_src), btree_storage))
+}
+
+/// Reads the layouts starting with a discriminator and version, returning
+/// the data following the BTreeMap along with it
+#[allow(clippy::ptr_offset_with_cast, clippy::type_complexity)]
+fn unpack_versioned(
+    src: &[u8],
+) -> Result<(&[u8; DISCRIMINATOR_BYTES], bool, Pubkey, BTreeMap<String, String>, &[u8]), StateError>
+{
+    if src.len() < ACCOUNT_STATE_HEADER {
+        return Err(StateError::LengthOutOfRange {
+            len: ACCOUNT_STATE_HEADER,
+            capacity: src.len(),
+        });
+    }
+    let (header_src, data_src) = src.split_at(ACCOUNT_STATE_HEADER);
+    let header_src = array_ref![header_src, 0, ACCOUNT_STATE_HEADER];
+    // Setup pointers to key areas of account state data
+    let (discriminator_src, _, is_initialized_src, owner_src, data_len_src) = array_refs![
+        header_src,
+        DISCRIMINATOR_BYTES,
+        VERSION_BYTES,
+        INITIALIZED_BYTES,
+        OWNER_BYTES,
+        BTREE_LENGTH
+    ];
+    let is_initialized = unpack_initialized(is_initialized_src)?;
+    let owner = Pubkey::new_from_array(*owner_src);
+    let btree_storage = unpack_btree(data_len_src, data_src)?;
+    // The BTreeMap is known to fit the data area by now
     let data_len = u32::from_le_bytes(*data_len_src) as usize;
+    Ok((discriminator_src, is_initialized, owner, btree_storage, &data_src[data_len..]))
+}
+
+/// Reads the initialization flag
+fn unpack_initialized(is_initialized_src: &[u8; INITIALIZED_BYTES]) -> Result<bool, StateError> {
+    match is_initialized_src {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        [flag] => Err(StateError::InvalidInitializedFlag(*flag)),
+    }
+}
+
+/// Reads the BTreeMap container from the data area
+fn unpack_btree(
+    data_len_src: &[u8; BTREE_LENGTH],
+    data_src: &[u8],
+) -> Result<BTreeMap<String, String>, StateError> {
+    // Get current size of content in data area, which can not be trusted
+    // to fit the account
+    let data_len = u32::from_le_bytes(*data_len_src) as usize;
+    if data_len > data_src.len() {
+        return Err(StateError::LengthOutOfRange {
+            len: data_len,
+            capacity: data_src.len(),
+        });
+    }
     // If emptry, create a default
     if data_len == 0 {
-        Ok((is_initialized, BTreeMap::<String, String>::new()))
+        Ok(BTreeMap::<String, String>::new())
     } else {
-        let data_dser = BTreeMap::<String, String>::try_from_slice(&data_src[0..data_len]).unwrap();
-        Ok((is_initialized, data_dser))
+        BTreeMap::<String, String>::try_from_slice(&data_src[0..data_len])
+            .map_err(|error| StateError::BorshFailure(error.to_string()))
+    }
+}
+
+/// Reads the account extension following the BTreeMap
+fn unpack_extension(extension_src: &[u8]) -> Result<Vec<u8>, StateError> {
+    if extension_src.len() < EXTENSION_LENGTH {
+        return Err(StateError::LengthOutOfRange {
+            len: EXTENSION_LENGTH,
+            capacity: extension_src.len(),
+        });
+    }
+    let (extension_len_src, extension_src) = extension_src.split_at(EXTENSION_LENGTH);
+    let extension_len = u32::from_le_bytes(*array_ref![extension_len_src, 0, EXTENSION_LENGTH]);
+    let extension_len = extension_len as usize;
+    if extension_len > extension_src.len() {
+        return Err(StateError::LengthOutOfRange {
+            len: extension_len,
+            capacity: extension_src.len(),
+        });
     }
+    Ok(extension_src[..extension_len].to_vec())
 }
 
-/// Packs the initialized flag and data content into destination slice
+/// Packs the account type, initialized flag, owner, data content and
+/// extension into destination slice using the current layout version
 #[allow(clippy::ptr_offset_with_cast)]
 pub fn pack_into_slice(
+    account_type: AccountType,
     is_initialized: bool,
+    owner: &Pubkey,
     btree_storage: &BTreeMap<String, String>,
+    extension: &[u8],
     dst: &mut [u8],
-) {
-    let dst = array_mut_ref![dst, 0, ACCOUNT_STATE_SPACE];
+) -> Result<(), StateError> {
+    if dst.len() < ACCOUNT_STATE_HEADER {
+        return Err(StateError::LengthOutOfRange {
+            len: ACCOUNT_STATE_HEADER,
+            capacity: dst.len(),
+        });
+    }
+    let (header_dst, data_dst) = dst.split_at_mut(ACCOUNT_STATE_HEADER);
+    let header_dst = array_mut_ref![header_dst, 0, ACCOUNT_STATE_HEADER];
     // Setup pointers to key areas of account state data
-    let (is_initialized_dst, data_len_dst, data_dst) =
-        mut_array_refs![dst, INITIALIZED_BYTES, BTREE_LENGTH, BTREE_STORAGE];
-    // Set the initialized flag
-    is_initialized_dst[0] = is_initialized as u8;
+    let (discriminator_dst, version_dst, is_initialized_dst, owner_dst, data_len_dst) =
+        mut_array_refs![
+            header_dst,
+            DISCRIMINATOR_BYTES,
+            VERSION_BYTES,
+            INITIALIZED_BYTES,
+            OWNER_BYTES,
+            BTREE_LENGTH
+        ];
     // Store the core data length and serialized content
-    let keyval_store_data = btree_storage.try_to_vec().unwrap();
+    let keyval_store_data = btree_storage
+        .try_to_vec()
+        .map_err(|error| StateError::BorshFailure(error.to_string()))?;
     let data_len = keyval_store_data.len();
-    if data_len < BTREE_STORAGE {
-        data_len_dst[..].copy_from_slice(&(data_len as u32).to_le_bytes());
-        sol_memcpy(data_dst, &keyval_store_data, data_len);
-    } else {
-        panic!();
+    let extension_len = extension.len();
+    let total_len = data_len + EXTENSION_LENGTH + extension_len;
+    if total_len > data_dst.len() {
+        return Err(StateError::Overflow {
+            len: total_len,
+            capacity: data_dst.len(),
+        });
+    }
+    // Set the header, initialized flag and owner
+    discriminator_dst.copy_from_slice(&account_type.discriminator());
+    version_dst[0] = LAYOUT_VERSION;
+    is_initialized_dst[0] = is_initialized as u8;
+    owner_dst.copy_from_slice(owner.as_ref());
+    data_len_dst[..].copy_from_slice(&(data_len as u32).to_le_bytes());
+    sol_memcpy(data_dst, &keyval_store_data, data_len);
+    let extension_dst = &mut data_dst[data_len..];
+    extension_dst[..EXTENSION_LENGTH].copy_from_slice(&(extension_len as u32).to_le_bytes());
+    sol_memcpy(&mut extension_dst[EXTENSION_LENGTH..], extension, extension_len);
+    Ok(())
+}
+
+/// Reads the header of an account without account state, returning its
+/// account type, layout version and the content following the header
+pub fn unpack_typed_header(src: &[u8]) -> Result<(AccountType, u8, &[u8]), StateError> {
+    if src.len() < TYPED_HEADER {
+        return Err(StateError::LengthOutOfRange {
+            len: TYPED_HEADER,
+            capacity: src.len(),
+        });
+    }
+    let (header_src, content_src) = src.split_at(TYPED_HEADER);
+    let account_type = AccountType::from_discriminator(&header_src[..DISCRIMINATOR_BYTES])
+        .ok_or(StateError::InvalidDiscriminator)?;
+    if account_type.has_account_state() {
+        return Err(StateError::UnexpectedAccountType(account_type));
+    }
+    Ok((account_type, header_src[DISCRIMINATOR_BYTES], content_src))
+}
+
+/// Writes the header of an account without account state, returning the
+/// slice left for its content
+pub fn pack_typed_header(
+    account_type: AccountType,
+    version: u8,
+    dst: &mut [u8],
+) -> Result<&mut [u8], StateError> {
+    if dst.len() < TYPED_HEADER {
+        return Err(StateError::LengthOutOfRange {
+            len: TYPED_HEADER,
+            capacity: dst.len(),
+        });
+    }
+    let (header_dst, content_dst) = dst.split_at_mut(TYPED_HEADER);
+    header_dst[..DISCRIMINATOR_BYTES].copy_from_slice(&account_type.discriminator());
+    header_dst[DISCRIMINATOR_BYTES] = version;
+    Ok(content_dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INITIALIZED_OFFSET: usize = DISCRIMINATOR_BYTES + VERSION_BYTES;
+    const DATA_LENGTH_OFFSET: usize = INITIALIZED_OFFSET + INITIALIZED_BYTES + OWNER_BYTES;
+
+    fn packed_account() -> (BTreeMap<String, String>, Vec<u8>) {
+        let mut btree_storage = BTreeMap::new();
+        btree_storage.insert("key".to_string(), "value".to_string());
+        let mut dst = vec![0; packed_len(&btree_storage, 0)];
+        pack_into_slice(
+            AccountType::KeyValueStore,
+            true,
+            &Pubkey::default(),
+            &btree_storage,
+            &[],
+            &mut dst,
+        )
+        .unwrap();
+        (btree_storage, dst)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (btree_storage, src) = packed_account();
+        let (account_type, is_initialized, owner, unpacked_storage, extension) =
+            unpack_from_slice(&src).unwrap();
+        assert_eq!(account_type, AccountType::KeyValueStore);
+        assert!(is_initialized);
+        assert_eq!(owner, Pubkey::default());
+        assert_eq!(unpacked_storage, btree_storage);
+        assert!(extension.is_empty());
+    }
+
+    /// Packs the data content the way the legacy layouts did, with or
+    /// without an owner, into an account of the given size
+    fn legacy_account(
+        owner: Option<&Pubkey>,
+        btree_storage: &BTreeMap<String, String>,
+        space: usize,
+    ) -> Vec<u8> {
+        let btree_data = btree_storage.try_to_vec().unwrap();
+        let mut src = vec![1];
+        if let Some(owner) = owner {
+            src.extend_from_slice(owner.as_ref());
+        }
+        src.extend_from_slice(&(btree_data.len() as u32).to_le_bytes());
+        src.extend_from_slice(&btree_data);
+        src.resize(space.max(src.len()), 0);
+        src
+    }
+
+    #[test]
+    fn test_unpack_legacy_layouts() {
+        let mut btree_storage = BTreeMap::new();
+        btree_storage.insert("a1".to_string(), "b1".to_string());
+        let owner = Pubkey::new_from_array([7; OWNER_BYTES]);
+        let no_owner = Pubkey::default();
+        let cases = [
+            // Without owner, at its fixed size
+            (legacy_account(None, &btree_storage, 1024), no_owner),
+            // With an owner, at its fixed size and sized to its content
+            (legacy_account(Some(&owner), &btree_storage, 1056), owner),
+            (legacy_account(Some(&owner), &btree_storage, 0), owner),
+            (legacy_account(Some(&no_owner), &btree_storage, 0), no_owner),
+        ];
+        for (src, expected_owner) in cases {
+            assert_eq!(layout_version(&src), Ok(LEGACY_LAYOUT_VERSION));
+            let (account_type, is_initialized, owner, unpacked_storage, extension) =
+                unpack_from_slice(&src).unwrap();
+            let expected_type = match expected_owner == Pubkey::default() {
+                true => AccountType::KeyValueStore,
+                false => AccountType::Wallet,
+            };
+            assert_eq!(account_type, expected_type);
+            assert!(is_initialized);
+            assert_eq!(owner, expected_owner);
+            assert_eq!(unpacked_storage, btree_storage);
+            assert!(extension.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_unpack_invalid_initialized_flag() {
+        let (_, mut src) = packed_account();
+        src[INITIALIZED_OFFSET] = 2;
+        assert_eq!(
+            unpack_from_slice(&src),
+            Err(StateError::InvalidInitializedFlag(2))
+        );
+    }
+
+    #[test]
+    fn test_unpack_length_out_of_range() {
+        let (_, src) = packed_account();
+        assert_eq!(
+            unpack_from_slice(&src[..ACCOUNT_STATE_HEADER - 1]),
+            Err(StateError::LengthOutOfRange {
+                len: ACCOUNT_STATE_HEADER,
+                capacity: ACCOUNT_STATE_HEADER - 1,
+            })
+        );
+        let mut src = src;
+        let capacity = src.len() - ACCOUNT_STATE_HEADER;
+        src[DATA_LENGTH_OFFSET..ACCOUNT_STATE_HEADER].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            unpack_from_slice(&src),
+            Err(StateError::LengthOutOfRange {
+                len: u32::MAX as usize,
+                capacity,
+            })
+        );
+        // The extension length is no more trusted than the data length
+        let (_, mut src) = packed_account();
+        let extension_len_offset = src.len() - EXTENSION_LENGTH;
+        src[extension_len_offset..].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(
+            unpack_from_slice(&src),
+            Err(StateError::LengthOutOfRange {
+                len: 1,
+                capacity: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_unpack_borsh_failure() {
+        let (_, mut src) = packed_account();
+        // The map claims more pairs than the data holds
+        src[ACCOUNT_STATE_HEADER..ACCOUNT_STATE_HEADER + BTREE_LENGTH]
+            .copy_from_slice(&2u32.to_le_bytes());
+        let result = unpack_from_slice(&src);
+        assert!(matches!(result, Err(StateError::BorshFailure(_))));
+    }
+
+    #[test]
+    fn test_unpack_unknown_layout() {
+        let (_, mut src) = packed_account();
+        src[DISCRIMINATOR_BYTES] = LAYOUT_VERSION + 1;
+        assert_eq!(
+            unpack_from_slice(&src),
+            Err(StateError::UnsupportedVersion(LAYOUT_VERSION + 1))
+        );
+        src[..DISCRIMINATOR_BYTES].copy_from_slice(b"unknown\0");
+        assert_eq!(
+            unpack_from_slice(&src),
+            Err(StateError::InvalidDiscriminator)
+        );
+        src[..DISCRIMINATOR_BYTES].copy_from_slice(&CONFIG_DISCRIMINATOR);
+        assert_eq!(
+            unpack_from_slice(&src),
+            Err(StateError::UnexpectedAccountType(AccountType::Config))
+        );
+    }
+
+    #[test]
+    fn test_pack_overflow() {
+        let (btree_storage, src) = packed_account();
+        let mut dst = vec![0; src.len() - 1];
+        assert_eq!(
+            pack_into_slice(
+                AccountType::KeyValueStore,
+                true,
+                &Pubkey::default(),
+                &btree_storage,
+                &[],
+                &mut dst,
+            ),
+            Err(StateError::Overflow {
+                len: src.len() - ACCOUNT_STATE_HEADER,
+                capacity: dst.len() - ACCOUNT_STATE_HEADER,
+            })
+        );
+        let mut dst = vec![0; ACCOUNT_STATE_HEADER - 1];
+        assert_eq!(
+            pack_into_slice(
+                AccountType::KeyValueStore,
+                true,
+                &Pubkey::default(),
+                &btree_storage,
+                &[],
+                &mut dst,
+            ),
+            Err(StateError::LengthOutOfRange {
+                len: ACCOUNT_STATE_HEADER,
+                capacity: ACCOUNT_STATE_HEADER - 1,
+            })
+        );
     }
 }